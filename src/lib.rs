@@ -7,7 +7,7 @@
 //! ## Examples
 //!
 //! ```
-//! let mut db = mu_db::DataBase::new("./test.db");
+//! let mut db = mu_db::DataBase::new("./test.db").unwrap();
 //! // This will generate ./test.db and ./index_test.db if they don't exist.
 //!
 //! db.insert("key", "before_value");
@@ -16,7 +16,7 @@
 //! let value = db.get("key");
 //! assert_eq!(value, Some("after_value".to_string()));
 //!
-//! db.remove("key");
+//! db.remove("key").unwrap();
 //!
 //! assert_eq!(db.get("key"), None);
 //! assert!(db.is_empty()); // index is empty
@@ -40,448 +40,5478 @@
 //! Please note that the mu_db is a simple, lightweight database and does not support complex database operations like transactions, joins, etc. It is best suited for simple key-value storage needs.
 
 use std::{
+    borrow::Cow,
+    collections::{hash_map::DefaultHasher, BTreeMap, HashMap, HashSet, VecDeque},
+    fmt,
     fs::{File, OpenOptions},
-    io::{BufReader, BufWriter, Read, Result, Seek, SeekFrom, Write},
+    hash::{BuildHasherDefault, Hash, Hasher},
+    io::{BufRead, BufReader, BufWriter, Read, Result, Seek, SeekFrom, Write},
     ops::Range,
-    path::Path,
-    sync::{Arc, Mutex},
+    path::{Path, PathBuf},
+    sync::{mpsc, Arc, Mutex, MutexGuard, RwLock, RwLockReadGuard, RwLockWriteGuard},
+    time::{Duration, Instant},
 };
 
+/// Maximum number of bytes (`.shrink_sequential()`) buffers between writes.
+pub const SHRINK_BUFFER_SIZE: usize = 1 << 20;
+
+/// Last 8 bytes of a db file whose index is stored in the same file, see
+/// (`DataBaseBuilder::single_file()`). The last byte is the format version; opening a file
+/// with another version fails instead of misreading it.
+///
+/// The index length before it is always stored little endian, whatever the host byte order.
+/// # Example
+/// ```
+/// // the end of this test corrupts the file, so start from a fresh one
+/// let _ = std::fs::remove_file("./test_embedded_magic.db");
+/// let mut db = mu_db::DataBase::builder("./test_embedded_magic.db")
+///     .single_file(true)
+///     .open()
+///     .unwrap();
+/// db.clear_all().unwrap();
+/// db.insert("k", "v");
+/// let file = std::fs::read("./test_embedded_magic.db").unwrap();
+/// let (rest, magic) = file.split_at(file.len() - 8);
+/// assert_eq!(magic, mu_db::EMBEDDED_INDEX_MAGIC);
+/// let index_len = u64::from_le_bytes(rest[rest.len() - 8..].try_into().unwrap());
+/// assert_eq!(index_len as usize, "k=0_1=version:1\n".len());
+/// drop(db);
+///
+/// let mut file = file;
+/// *file.last_mut().unwrap() = b'9';
+/// std::fs::write("./test_embedded_magic.db", file).unwrap();
+/// let opened = mu_db::DataBase::builder("./test_embedded_magic.db")
+///     .single_file(true)
+///     .open();
+/// assert!(opened.is_err());
+/// ```
+pub const EMBEDDED_INDEX_MAGIC: &[u8; 8] = b"muDBidx1";
+
+/// First 8 bytes of an index file in the binary format, the last byte is the format version,
+/// see (`DataBaseBuilder::binary_index()`).
+///
+/// The header is followed by one record per entry: the key length as a little-endian `u32`,
+/// the key, the range start and end as little-endian `u64`s, then the length of the optional
+/// fields as a `u32` and the fields as they appear after the range in a text index line.
+///
+/// An index file is binary if it starts with the header for any version, opening one with
+/// another version fails with a [VersionMismatch] error. A leading `m` of a key is escaped in
+/// a text index, so a text index never starts with the header.
+/// # Example
+/// ```
+/// let mut db = mu_db::DataBase::new("./test_binary_magic_key.db").unwrap();
+/// db.clear_all().unwrap();
+/// db.insert("muDBbinary", "v");
+/// db.insert("muDBbin1", "w");
+/// drop(db);
+/// let index = std::fs::read_to_string("./index_test_binary_magic_key.db").unwrap();
+/// assert!(index.starts_with("\\muDBbinary="));
+///
+/// let mut db = mu_db::DataBase::new("./test_binary_magic_key.db").unwrap();
+/// assert_eq!(db.get("muDBbinary"), Some("v".to_string()));
+/// assert_eq!(db.get("muDBbin1"), Some("w".to_string()));
+/// ```
+pub const BINARY_INDEX_MAGIC: &[u8; 8] = b"muDBbin1";
+
+/// Last 8 bytes of a stream written by (`.freeze()`), the last byte is the format version.
+pub const FROZEN_MAGIC: &[u8; 8] = b"muDBfrz1";
+
+/// On-disk format version written by this version of muDB, see (`DataBase::format_version()`).
+pub const FORMAT_VERSION: u32 = 1;
+
+/// Maximum length of a key in bytes, see (`DataBase::is_valid_key()`).
+pub const MAX_KEY_LEN: usize = 1024;
+
+/// Fraction of reclaimable bytes in the db file at which (`.health()`) recommends a shrink.
+pub const SHRINK_RECOMMENDED_RATIO: f64 = 0.25;
+
 pub struct DataBase {
     index: Index,
     reader: Arc<Mutex<BufReader<File>>>,
     writer: Arc<Mutex<BufWriter<File>>>,
+    options: Options,
+    /// Turned off by (`.set_auto_flush()`), writes then only reach the OS when the buffer fills.
+    auto_flush: bool,
+    last_inserted: Option<String>,
+    path: PathBuf,
+    /// Length of the db file counting writes still buffered, kept up to date by every write
+    /// so (`.buf_len()`) doesn't have to flush and stat the file.
+    data_len: u64,
+    /// Queue of the data writer thread, see [Options::background_data_writes].
+    background: Option<mpsc::Sender<DataJob>>,
 }
 
-#[derive(Clone)]
-pub struct Index {
-    entries: Vec<IndexEntry>,
-    writer: Arc<Mutex<BufWriter<File>>>,
+/// Options set with [DataBaseBuilder].
+#[derive(Clone, Debug, Default)]
+pub struct Options {
+    /// Advise the OS to drop the db file from the page cache after shrinking.
+    pub drop_cache_after_shrink: bool,
+    /// How the index finds the entry of a key.
+    pub key_lookup: KeyLookup,
+    /// What happens to the freed tail when a value is overwritten with a smaller one.
+    pub shrink_tail: ShrinkTailPolicy,
+    /// How many times a read or write that failed with a transient error is retried.
+    pub io_retries: usize,
+    /// Store the index at the end of the db file instead of a separate index file.
+    pub single_file: bool,
+    /// Defer index writes of mutations, `None` writes the index on every mutation.
+    pub coalesce_index_writes: Option<IndexWriteCoalescing>,
+    /// Transforms values on their way to and from the db file, `None` stores them as is.
+    pub codec: Option<Arc<dyn ValueCodec>>,
+    /// Flush the data writer once this many bytes are buffered, `None` flushes every write.
+    pub flush_every_bytes: Option<usize>,
+    /// Bytes at the start of the db file that values are never placed in.
+    pub reserve_prefix: usize,
+    /// Number of index entries to allocate room for when opening.
+    pub index_capacity: usize,
+    /// Bytes each (`.insert()`) and (`.remove()`) may move to compact the db file, `None`
+    /// leaves compaction to (`.shrink()`).
+    pub compaction_budget: Option<usize>,
+    /// Run (`.shrink()`) after an (`.insert()`) or (`.remove()`) that leaves
+    /// (`.fragmentation()`) above this, `None` never shrinks automatically.
+    pub auto_shrink: Option<f64>,
+    /// Write the index file on a separate thread, so mutations don't wait for it. Not used
+    /// with [Options::single_file].
+    pub background_index_writes: bool,
+    /// Write values to the db file on a separate thread, reads wait for the queued writes.
+    /// Not used with [Options::single_file].
+    pub background_data_writes: bool,
+    /// Values shorter than this many bytes are stored in the index instead of the db file,
+    /// `0` stores every value in the db file.
+    pub inline_threshold: usize,
+    /// Write the index file in the binary format, see [BINARY_INDEX_MAGIC]. An index file
+    /// that is binary already stays binary. Not used with [Options::single_file].
+    pub binary_index: bool,
+    /// Write a CRC-32 checksum with every index entry, so a damaged entry fails to load
+    /// instead of pointing at the wrong bytes.
+    pub index_checksums: bool,
+    /// Keep about this many index entries in memory and look the others up in the index file,
+    /// `None` keeps every entry in memory.
+    pub max_index_entries: Option<usize>,
 }
 
-#[derive(Clone)]
-pub struct IndexEntry {
-    key: String,
-    range: Range<usize>,
+/// Transforms values written by (`.insert()`) and read by (`.get()`), e.g. to compress,
+/// encrypt or frame them, see (`DataBaseBuilder::codec()`).
+///
+/// The index range of an entry covers the encoded bytes. Raw access like (`.read_at()`),
+/// (`.read_batch()`), (`.for_each_bytes()`) and (`.search_values()`) sees the encoded bytes.
+pub trait ValueCodec: fmt::Debug + Send + Sync {
+    fn encode(&self, value: &[u8]) -> Vec<u8>;
+    /// Returns an error (usually `InvalidData`) if `stored` wasn't produced by `encode`.
+    fn decode(&self, stored: &[u8]) -> Result<Vec<u8>>;
 }
 
-impl DataBase {
-    /// Creates a new instance of the database or uses the existing db file,
-    /// at the given path.
+/// Bounds on how long index writes are deferred, see
+/// (`DataBaseBuilder::coalesce_index_writes()`).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct IndexWriteCoalescing {
+    /// The index is written by the first mutation at least this long after the last write.
+    pub max_delay: Duration,
+    /// The index is written once this many mutations are pending.
+    pub max_mutations: usize,
+}
+
+/// Builder for opening a [DataBase] with non-default [Options].
+/// # Example
+/// ```
+/// let db = mu_db::DataBase::builder("./test_builder.db")
+///     .drop_cache_after_shrink(true)
+///     .open()
+///     .unwrap();
+/// assert!(db.options().drop_cache_after_shrink);
+/// ```
+#[derive(Clone, Debug)]
+pub struct DataBaseBuilder {
+    path: String,
+    options: Options,
+}
+
+impl DataBaseBuilder {
+    /// After (`.shrink()`) and (`.shrink_sequential()`) advise the OS that the db file pages
+    /// won't be needed (`posix_fadvise(POSIX_FADV_DONTNEED)`), so compacting a large file
+    /// doesn't evict the application's hot pages. Does nothing on non-Linux targets.
+    pub fn drop_cache_after_shrink(mut self, enabled: bool) -> Self {
+        self.options.drop_cache_after_shrink = enabled;
+        self
+    }
+    /// Sets how the index finds the entry of a key, see [KeyLookup].
     /// # Example
     /// ```
-    /// let db = mu_db::DataBase::new("./test.db");
+    /// use mu_db::KeyLookup;
+    /// for lookup in [KeyLookup::BinarySearch, KeyLookup::Hash] {
+    ///     let mut db = mu_db::DataBase::builder("./test_key_lookup.db")
+    ///         .key_lookup(lookup)
+    ///         .open()
+    ///         .unwrap();
+    ///     db.clear_all().unwrap();
+    ///     for k in ["m", "c", "x", "a", "q"] {
+    ///         db.insert(k, k);
+    ///     }
+    ///     db.remove("c").unwrap();
+    ///     db.insert("x", "longer value");
+    ///     db.insert("b", "b");
+    ///     assert_eq!(db.get("a"), Some("a".to_string()));
+    ///     assert_eq!(db.get("b"), Some("b".to_string()));
+    ///     assert_eq!(db.get("c"), None);
+    ///     assert_eq!(db.get("x"), Some("longer value".to_string()));
+    ///     assert_eq!(db.get("q"), Some("q".to_string()));
+    /// }
     /// ```
-    /// Generates (`./test.db`) and (`./index_test.db`) if doesn't exist.
-    pub fn new(path: &str) -> Self {
-        let file = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true)
-            .open(path)
-            .unwrap();
-
-        let file_clone = file.try_clone().unwrap();
-
-        let _path = Path::new(path);
-        let db_file_name = _path.file_name().and_then(|i| i.to_str()).unwrap();
-        let db_file_parent = _path
-            .parent()
-            .unwrap()
-            .to_str()
-            .and_then(|i| if i == "" { Some(".") } else { Some(i) })
-            .unwrap();
-        let index_file_path = format!("{}/{}", db_file_parent, format!("index_{}", db_file_name));
-
-        let index = Index::new(&index_file_path);
-
-        DataBase {
-            index,
-            reader: Arc::new(Mutex::new(BufReader::new(file))),
-            writer: Arc::new(Mutex::new(BufWriter::new(file_clone))),
-        }
+    pub fn key_lookup(mut self, lookup: KeyLookup) -> Self {
+        self.options.key_lookup = lookup;
+        self
     }
-
-    /// Inserts a key-value pair into the database, replacing old value if key exists.
+    /// Sets what happens to the freed tail when a value is overwritten with a smaller one,
+    /// defaults to [ShrinkTailPolicy::Free].
     /// # Example
-    ///
     /// ```
-    /// let mut db = mu_db::DataBase::new("./test.db");
-    /// db.insert("key", "before");
-    /// db.insert("key", "after");
-    /// assert_eq!(db.get("key"), Some("after".to_string()));
+    /// use mu_db::ShrinkTailPolicy;
+    /// let mut db = mu_db::DataBase::builder("./test_shrink_tail.db")
+    ///     .shrink_tail(ShrinkTailPolicy::Free)
+    ///     .open()
+    ///     .unwrap();
+    /// db.clear_all().unwrap();
+    /// db.insert("k1", "1234567890");
+    /// db.insert("k2", "abc");
+    /// db.insert("k1", "12345");
+    /// db.insert("k3", "xyz"); // reuses the freed tail of k1
+    /// assert_eq!(db.buf_len(), 13);
+    ///
+    /// let mut db = mu_db::DataBase::builder("./test_shrink_tail.db")
+    ///     .shrink_tail(ShrinkTailPolicy::Keep)
+    ///     .open()
+    ///     .unwrap();
+    /// db.clear_all().unwrap();
+    /// db.insert("k1", "1234567890");
+    /// db.insert("k2", "abc");
+    /// db.insert("k1", "12345");
+    /// db.insert("k3", "xyz"); // appended, the tail still belongs to k1
+    /// assert_eq!(db.buf_len(), 16);
+    /// db.insert("k1", "1234567890"); // grows back in place
+    /// assert_eq!(db.buf_len(), 16);
+    /// assert_eq!(db.get("k1"), Some("1234567890".to_string()));
     /// ```
-    pub fn insert(&mut self, key: &str, value: &str) {
-        let value_len = value.len();
-        let index_entry = self.index.insert_entry(value_len, &key);
-        self.write_at(index_entry.range.start.try_into().unwrap(), value)
-            .unwrap();
+    pub fn shrink_tail(mut self, policy: ShrinkTailPolicy) -> Self {
+        self.options.shrink_tail = policy;
+        self
     }
-    /// Retrieves the value associated with the given key from the database.
+    /// Retries reads and writes of the db and index files up to `retries` times when they fail
+    /// with a transient error (`Interrupted`, `WouldBlock`, e.g. `EINTR`/`EAGAIN` on network
+    /// filesystems), waiting 1ms, 2ms, 4ms, ... (at most 100ms) between attempts.
+    /// Defaults to 0.
     /// # Example
     /// ```
-    /// let mut db = mu_db::DataBase::new("./test.db");
+    /// let mut db = mu_db::DataBase::builder("./test_io_retries.db")
+    ///     .io_retries(3)
+    ///     .open()
+    ///     .unwrap();
+    /// assert_eq!(db.options().io_retries, 3);
     /// db.insert("key", "value");
     /// assert_eq!(db.get("key"), Some("value".to_string()));
     /// ```
-    pub fn get(&mut self, key: &str) -> Option<String> {
-        let index_entry = self.index.get_entry(&key);
-        match index_entry {
-            Some(e) => Some(
-                self.read_at(e.range.start.try_into().unwrap(), e.size())
-                    .unwrap(),
-            ),
-            None => None,
-        }
+    pub fn io_retries(mut self, retries: usize) -> Self {
+        self.options.io_retries = retries;
+        self
     }
-    /// Removes the entry associated with the given key from the index if the key exists.
-    /// This method does not remove the value in the database file. To completely remove the value,
-    /// you need to use (`.shrink()`) after removing the entry.
+    /// Stores the index in the db file itself, so the database is a single portable file.
+    ///
+    /// The file holds the values, followed by the index text, its length (`u64` little
+    /// endian) and [EMBEDDED_INDEX_MAGIC]. The index block moves with the end of the values
+    /// and is rewritten by every mutation like the index file. (`.buf_len()`) and
+    /// (`.set_buf_len()`) only count the values. Opening a file without the trailer keeps its
+    /// bytes as values with an empty index.
+    /// # Example
+    /// ```
+    /// let mut db = mu_db::DataBase::builder("./test_single_file.db")
+    ///     .single_file(true)
+    ///     .open()
+    ///     .unwrap();
+    /// db.clear_all().unwrap();
+    /// db.insert("k1", "hello");
+    /// db.insert("k2", "world");
+    /// db.remove("k1").unwrap();
+    /// db.shrink();
+    /// assert_eq!(db.buf_len(), 5);
+    /// assert_eq!(db.index_path(), db.path());
+    /// assert!(!std::path::Path::new("./index_test_single_file.db").exists());
+    /// drop(db);
+    ///
+    /// let mut db = mu_db::DataBase::builder("./test_single_file.db")
+    ///     .single_file(true)
+    ///     .open()
+    ///     .unwrap();
+    /// assert_eq!(db.get("k2"), Some("world".to_string()));
+    /// assert_eq!(db.get("k1"), None);
+    /// ```
+    pub fn single_file(mut self, enabled: bool) -> Self {
+        self.options.single_file = enabled;
+        self
+    }
+    /// Defers the index write of mutations until `max_mutations` are pending or a mutation
+    /// happens `max_delay` after the last write, whichever comes first. Pending changes are
+    /// also written by (`.flush_all()`), (`.rewrite_index()`) and when the [DataBase] is
+    /// dropped.
+    ///
+    /// Cuts the cost of rewriting the whole index on bursty writes, at the price of losing
+    /// the deferred mutations on a crash. There is no background timer: after a burst the
+    /// index stays stale until the next mutation, flush or drop.
+    /// # Example
+    /// ```
+    /// use std::time::Duration;
+    /// let mut db = mu_db::DataBase::builder("./test_coalesce.db")
+    ///     .coalesce_index_writes(Duration::from_secs(3600), 10)
+    ///     .open()
+    ///     .unwrap();
+    /// db.clear_all().unwrap();
+    /// for i in 0..25 {
+    ///     db.insert(&format!("k{}", i), "value");
+    /// }
+    /// assert_eq!(db.index_writes(), 2);
+    /// db.flush_all().unwrap();
+    /// assert_eq!(db.index_writes(), 3);
+    /// db.insert("last", "value");
+    /// drop(db);
+    ///
+    /// let mut db = mu_db::DataBase::new("./test_coalesce.db").unwrap();
+    /// assert_eq!(db.get("k24"), Some("value".to_string()));
+    /// assert_eq!(db.get("last"), Some("value".to_string()));
+    /// ```
+    pub fn coalesce_index_writes(mut self, max_delay: Duration, max_mutations: usize) -> Self {
+        self.options.coalesce_index_writes = Some(IndexWriteCoalescing {
+            max_delay,
+            max_mutations,
+        });
+        self
+    }
+    /// Sets the [ValueCodec] applied to every value.
     /// # Example
     /// ```
-    /// let mut db = mu_db::DataBase::new("./test.db");
+    /// use std::io::{Error, ErrorKind, Result};
+    /// use std::sync::Arc;
+    ///
+    /// #[derive(Debug)]
+    /// struct Framed;
+    ///
+    /// impl mu_db::ValueCodec for Framed {
+    ///     fn encode(&self, value: &[u8]) -> Vec<u8> {
+    ///         [b"v1:", value].concat()
+    ///     }
+    ///     fn decode(&self, stored: &[u8]) -> Result<Vec<u8>> {
+    ///         match stored.strip_prefix(b"v1:") {
+    ///             Some(value) => Ok(value.to_vec()),
+    ///             None => Err(Error::new(ErrorKind::InvalidData, "missing frame")),
+    ///         }
+    ///     }
+    /// }
+    ///
+    /// let mut db = mu_db::DataBase::builder("./test_codec.db")
+    ///     .codec(Arc::new(Framed))
+    ///     .open()
+    ///     .unwrap();
+    /// db.clear_all().unwrap();
     /// db.insert("key", "value");
+    /// assert_eq!(db.buf_len(), 8);
+    /// assert_eq!(db.read_at(0, 8).unwrap(), "v1:value");
     /// assert_eq!(db.get("key"), Some("value".to_string()));
-    /// db.remove("key");
-    /// assert_eq!(db.get("key"), None);
+    /// assert_eq!(db.snapshot().get("key"), Some("value".to_string()));
+    ///
+    /// let shared = mu_db::SharedDataBase::new(db);
+    /// assert_eq!(shared.get("key"), Some("value".to_string()));
     /// ```
-    pub fn remove(&mut self, key: &str) {
-        self.index.remove_entry(&key);
+    pub fn codec(mut self, codec: Arc<dyn ValueCodec>) -> Self {
+        self.options.codec = Some(codec);
+        self
     }
-    /// Clears all data in the database.
+    /// Keeps up to `bytes` written values in the userspace buffer of the db file instead of
+    /// flushing every write, so runs of appends reach the OS in large writes. Reads, and
+    /// anything else that needs the file to be current, flush the buffer first; buffered
+    /// bytes are lost if the process dies before they're flushed. Doesn't fsync, see
+    /// (`.flush_all()`).
     /// # Example
     /// ```
-    /// let mut db = mu_db::DataBase::new("./test.db");
-    /// db.insert("key", "value");
-    /// assert!(!db.is_empty());
-    /// assert!(!db.is_buf_empty());
+    /// let mut db = mu_db::DataBase::builder("./test_flush_every_bytes.db")
+    ///     .flush_every_bytes(1 << 16)
+    ///     .open()
+    ///     .unwrap();
     /// db.clear_all().unwrap();
-    /// assert!(db.is_empty());
-    /// assert!(db.is_buf_empty());
+    /// for i in 0..100 {
+    ///     db.insert(&format!("k{}", i), "value");
+    /// }
+    /// assert_eq!(db.buf_len(), 500);
+    /// assert_eq!(db.get("k42"), Some("value".to_string()));
     /// ```
-    pub fn clear_all(&mut self) -> Result<()> {
-        self.set_buf_len(0);
-        self.index.clear_all();
-
-        Ok(())
+    pub fn flush_every_bytes(mut self, bytes: usize) -> Self {
+        self.options.flush_every_bytes = Some(bytes);
+        self
     }
-    /// Optimizes the database file by removing any unused space.
+    /// Keeps bytes `0..n` of the db file out of allocation and compaction, so the application
+    /// can store its own header there with (`.write_at()`) and (`.read_at()`). Only
+    /// (`.clear_all()`) and (`.set_buf_len()`) touch it.
     /// # Example
     /// ```
-    /// let mut db = mu_db::DataBase::new("./test.db");
-    /// db.clear_all();
-    /// db.insert("k1", "1".repeat(10).as_str());
-    /// db.insert("k2", "2".repeat(10).as_str());
-    /// assert_eq!(db.buf_len(), 20);
-    /// db.remove("k1");
-    /// assert_eq!(db.buf_len(), 20);
-    /// db.insert("k3", "3".repeat(5).as_str());
-    /// assert_eq!(db.buf_len(), 20);
+    /// let mut db = mu_db::DataBase::builder("./test_reserve_prefix.db")
+    ///     .reserve_prefix(4)
+    ///     .open()
+    ///     .unwrap();
+    /// db.clear_all().unwrap();
+    /// db.write_at(0, "HDR1").unwrap();
+    /// db.insert("k1", "hello");
+    /// db.insert("k2", "world");
+    /// assert_eq!(db.head("k1").unwrap().start, 4);
+    /// db.remove("k1").unwrap();
     /// db.shrink();
-    /// assert_eq!(db.buf_len(), 15);
-    /// db.remove("k2");
-    /// db.remove("k3");
-    /// assert_eq!(db.buf_len(), 15);
+    /// assert_eq!(db.head("k2").unwrap().start, 4);
+    /// db.remove("k2").unwrap();
     /// db.shrink();
-    /// assert_eq!(db.buf_len(), 0);
+    /// assert_eq!(db.read_at(0, 4).unwrap(), "HDR1");
+    /// assert_eq!(db.buf_len(), 4);
     /// ```
-    pub fn shrink(&mut self) {
-        if self.index.is_empty() {
-            self.clear_all().unwrap();
-            return;
-        }
-
-        let old_entries = self.index.shrink_entries();
-
-        for (old, new) in old_entries.iter().zip(self.index.entries.clone()) {
-            if old.range.start != new.range.start {
-                let old_string = self
-                    .read_at(old.range.start.try_into().unwrap(), old.size())
-                    .unwrap();
-                self.write_at(new.range.start.try_into().unwrap(), &old_string)
-                    .unwrap();
-            }
-        }
-
-        self.set_buf_len(
-            (self.index.entries.last().unwrap().range.end)
-                .try_into()
-                .unwrap(),
-        );
+    pub fn reserve_prefix(mut self, n: usize) -> Self {
+        self.options.reserve_prefix = n;
+        self
     }
-
-    /// Reads data directly from the database file at the specified position (`start`) and size (`size`).
+    /// Allocates room for `capacity` index entries up front, so loading a large index and the
+    /// inserts that follow don't repeatedly grow it.
+    pub fn index_capacity(mut self, capacity: usize) -> Self {
+        self.options.index_capacity = capacity;
+        self
+    }
+    /// Stores values shorter than `max_len` bytes in their index entry instead of the db file,
+    /// so (`.get()`) returns them without reading the db file. Ignored when a [ValueCodec] is
+    /// set.
+    ///
+    /// Every inline value is rewritten with the rest of the index on each index write, so
+    /// this only pays off for tiny values.
     /// # Example
     /// ```
-    /// let mut db = mu_db::DataBase::new("./test.db");
-    /// db.clear_all();
-    /// db.insert("k1", "hello");
-    /// db.insert("k2", "world");
-    /// assert_eq!(db.read_at(5, 5).unwrap(), "world".to_string());
+    /// let mut db = mu_db::DataBase::builder("./test_inline.db")
+    ///     .inline_threshold(16)
+    ///     .open()
+    ///     .unwrap();
+    /// db.clear_all().unwrap();
+    /// db.insert("small", "tiny");
+    /// db.insert("large", "definitely not tiny");
+    /// assert_eq!(db.buf_len(), 19);
+    /// assert_eq!(db.get("small"), Some("tiny".to_string()));
+    /// assert_eq!(db.get("large"), Some("definitely not tiny".to_string()));
+    /// assert_eq!(db.head("small").unwrap().size, 4);
+    /// let mut results = Vec::new();
+    /// db.read_batch(&["small"], &mut results).unwrap();
+    /// assert_eq!(results, vec![Some(b"tiny".to_vec())]);
+    ///
+    /// db.insert("large", "now tiny");
+    /// db.insert("padded", "a  ");
+    /// drop(db);
+    /// let mut db = mu_db::DataBase::new("./test_inline.db").unwrap();
+    /// assert_eq!(db.get("large"), Some("now tiny".to_string()));
+    /// assert_eq!(db.get("padded"), Some("a  ".to_string()));
     /// ```
-    pub fn read_at(&mut self, start: u64, size: usize) -> Result<String> {
-        let mut v = vec![0; size];
-        let mut br = self.reader.lock().unwrap();
-        br.seek(SeekFrom::Start(start))?;
-        br.read_exact(&mut v)?;
-        Ok(String::from_utf8_lossy(&v).into())
+    pub fn inline_threshold(mut self, max_len: usize) -> Self {
+        self.options.inline_threshold = max_len;
+        self
     }
-    /// Writes data directly to the database file at the specified position with any length.
+    /// Makes every (`.insert()`) and (`.remove()`) run (`.shrink_step(max_bytes)`), so free
+    /// space is reclaimed a little at a time instead of by long (`.shrink()`) calls.
     /// # Example
     /// ```
-    /// let mut db = mu_db::DataBase::new("./test.db");
-    /// db.clear_all();
-    /// db.write_at(5, "world").unwrap();
-    /// assert_eq!(db.read_at(5, 5).unwrap(), "world".to_string());
+    /// let mut db = mu_db::DataBase::builder("./test_amortized_compaction.db")
+    ///     .amortized_compaction(64)
+    ///     .open()
+    ///     .unwrap();
+    /// db.clear_all().unwrap();
+    /// for i in 0..10 {
+    ///     db.insert(&format!("k{}", i), "0123456789");
+    /// }
+    /// for i in 0..5 {
+    ///     db.remove(&format!("k{}", i)).unwrap();
+    /// }
+    /// db.insert("k0", "x");
+    /// assert_eq!(db.buf_len(), 51);
+    /// assert_eq!(db.get("k9"), Some("0123456789".to_string()));
     /// ```
-    pub fn write_at(&mut self, start: u64, content: &str) -> Result<()> {
-        let mut bw = self.writer.lock().unwrap();
-        bw.seek(SeekFrom::Start(start))?;
-        bw.write_all(content.as_bytes())?;
-        bw.flush()?;
-        Ok(())
+    pub fn amortized_compaction(mut self, max_bytes: usize) -> Self {
+        self.options.compaction_budget = Some(max_bytes);
+        self
     }
-    /// Returns `true` if `self.index.entries` is empty, and `false` otherwise.
+    /// Makes every (`.insert()`) and (`.remove()`) that leaves more than `threshold` of the db
+    /// file unused, see (`.fragmentation()`), run (`.shrink()`). With `0.5` the file is
+    /// compacted once dead space exceeds half of it.
+    /// # Example
+    /// ```
+    /// let mut db = mu_db::DataBase::builder("./test_auto_shrink.db")
+    ///     .auto_shrink(0.5)
+    ///     .open()
+    ///     .unwrap();
+    /// db.clear_all().unwrap();
+    /// for i in 0..4 {
+    ///     db.insert(&format!("k{}", i), "0123456789");
+    /// }
+    /// db.remove("k0").unwrap();
+    /// db.remove("k1").unwrap();
+    /// assert_eq!(db.buf_len(), 40);
+    /// db.remove("k2").unwrap();
+    /// assert_eq!(db.buf_len(), 10);
+    /// assert_eq!(db.get("k3"), Some("0123456789".to_string()));
+    /// ```
+    pub fn auto_shrink(mut self, threshold: f64) -> Self {
+        self.options.auto_shrink = Some(threshold);
+        self
+    }
+    /// Writes the index file on a dedicated thread: each mutation queues the new index and
+    /// returns, the thread writes only the latest of the queued ones. (`.flush_all()`),
+    /// (`.drain()`) and dropping the database wait until the queue is written.
     ///
-    /// If you want to know if db file is empty, use (`.is_buf_empty()`).
+    /// Values are written on the calling thread unless (`.background_data_writes()`) is set,
+    /// and the in-memory index is updated right away, so (`.get()`) sees every insert. A crash
+    /// loses the queued index writes. Ignored with (`.single_file()`).
     /// # Example
     /// ```
-    /// let mut db = mu_db::DataBase::new("./test.db");
-    /// db.clear_all();
-    /// db.insert("key", "value");
-    /// assert!(!db.is_empty());
-    /// assert!(!db.is_buf_empty());
-    /// db.remove("key");
-    /// assert!(db.is_empty());
-    /// assert!(!db.is_buf_empty());
-    /// db.shrink();
-    /// assert!(db.is_empty());
-    /// assert!(db.is_buf_empty());
+    /// let mut db = mu_db::DataBase::builder("./test_background_index_writes.db")
+    ///     .background_index_writes(true)
+    ///     .open()
+    ///     .unwrap();
+    /// db.clear_all().unwrap();
+    /// for i in 0..100 {
+    ///     db.insert(&format!("k{}", i), "value");
+    /// }
+    /// assert_eq!(db.get("k99"), Some("value".to_string()));
+    /// db.drain().unwrap();
+    /// let index = std::fs::read_to_string("./index_test_background_index_writes.db").unwrap();
+    /// assert_eq!(index.lines().count(), 100);
     /// ```
+    pub fn background_index_writes(mut self, enabled: bool) -> Self {
+        self.options.background_index_writes = enabled;
+        self
+    }
+    /// Writes values to the db file on a dedicated thread: each write queues the bytes and
+    /// returns, the thread writes them in order. Together with
+    /// (`.background_index_writes()`) mutations don't wait for any file write.
     ///
-    pub fn is_empty(&self) -> bool {
-        self.index.is_empty()
+    /// Reading the db file, e.g. (`.get()`) or (`.iter()`), first waits until the queued
+    /// writes are done, so reads see every insert. (`.flush()`), (`.drain()`), dropping the
+    /// database and the methods that truncate or copy the db file wait too. A failed write is
+    /// returned by the next of these, the ones that don't return a `Result` panic like for a
+    /// failed read. A crash loses the queued writes. Ignored with (`.single_file()`).
+    /// # Example
+    /// ```
+    /// let mut db = mu_db::DataBase::builder("./test_background_data_writes.db")
+    ///     .background_data_writes(true)
+    ///     .open()
+    ///     .unwrap();
+    /// db.clear_all().unwrap();
+    /// for i in 0..100 {
+    ///     db.insert(&format!("k{}", i), "value");
+    /// }
+    /// assert_eq!(db.get("k99"), Some("value".to_string()));
+    /// db.drain().unwrap();
+    /// let len = std::fs::metadata("./test_background_data_writes.db").unwrap().len();
+    /// assert_eq!(len, 500);
+    /// db.insert("k0", "other");
+    /// assert_eq!(db.get("k0"), Some("other".to_string()));
+    /// ```
+    pub fn background_data_writes(mut self, enabled: bool) -> Self {
+        self.options.background_data_writes = enabled;
+        self
     }
-    /// Returns `true` if db file has metadata length of 0, and `false` otherwise.
+    /// Writes the index file in a binary format instead of text lines, see
+    /// [BINARY_INDEX_MAGIC]: keys are stored with their length instead of escaped, so large
+    /// indexes load and save faster. The format of an existing index file is detected when
+    /// opening, so a binary index is read without this option and converted on the next write
+    /// if it's set.
     /// # Example
     /// ```
-    /// let mut db = mu_db::DataBase::new("./test.db");
-    /// db.clear_all();
-    /// assert!(db.is_buf_empty());
-    /// db.insert("key", "value");
-    /// assert!(!db.is_buf_empty());
+    /// let mut db = mu_db::DataBase::builder("./test_binary_index.db")
+    ///     .binary_index(true)
+    ///     .open()
+    ///     .unwrap();
+    /// db.clear_all().unwrap();
+    /// db.insert("key=1", "value");
+    /// drop(db);
+    /// let index = std::fs::read("./index_test_binary_index.db").unwrap();
+    /// assert!(index.starts_with(mu_db::BINARY_INDEX_MAGIC));
+    ///
+    /// let mut db = mu_db::DataBase::new("./test_binary_index.db").unwrap();
+    /// assert_eq!(db.get("key=1"), Some("value".to_string()));
     /// ```
-    pub fn is_buf_empty(&self) -> bool {
-        self.buf_len() == 0
+    pub fn binary_index(mut self, enabled: bool) -> Self {
+        self.options.binary_index = enabled;
+        self
     }
-    /// Returns the length of the db file matadata.
+    /// Writes a CRC-32 checksum with every index entry (an `=crc:` field in the text format).
+    /// Opening an index with an entry that doesn't match its checksum fails with an
+    /// [IndexParseError] for that entry, (`DataBase::open_and_repair()`) opens the database
+    /// with the intact entries and reports the damaged ones. Entries without a checksum, e.g.
+    /// written before this was enabled, are accepted.
     /// # Example
     /// ```
-    /// let mut db = mu_db::DataBase::new("./test.db");
-    /// db.clear_all();
-    /// db.insert("key", "value");
-    /// assert_eq!(db.buf_len(), 5);
-    /// db.clear_all();
-    /// assert_eq!(db.buf_len(), 0);
+    /// let mut db = mu_db::DataBase::builder("./test_index_checksums.db")
+    ///     .index_checksums(true)
+    ///     .open()
+    ///     .unwrap();
+    /// db.clear_all().unwrap();
+    /// db.insert("k1", "hello");
+    /// db.insert("k2", "world");
+    /// drop(db);
+    /// let index = std::fs::read_to_string("./index_test_index_checksums.db").unwrap();
+    /// assert!(index.starts_with("k1=0_5=version:1=crc:"));
+    /// // a damaged range that still parses:
+    /// let damaged = index.replacen("k2=5_10", "k2=5_11", 1);
+    /// std::fs::write("./index_test_index_checksums.db", damaged).unwrap();
+    ///
+    /// let err = mu_db::DataBase::new("./test_index_checksums.db").err().unwrap();
+    /// let parse_err = err.get_ref().unwrap().downcast_ref::<mu_db::IndexParseError>();
+    /// assert_eq!(parse_err.unwrap().line, 2);
+    /// assert_eq!(parse_err.unwrap().reason, "checksum mismatch");
+    ///
+    /// let (mut db, report) =
+    ///     mu_db::DataBase::open_and_repair("./test_index_checksums.db").unwrap();
+    /// assert_eq!(report.unparsed_lines, vec![2]);
+    /// assert_eq!(db.get("k1"), Some("hello".to_string()));
+    /// assert_eq!(db.get("k2"), None);
     /// ```
-    pub fn buf_len(&self) -> u64 {
-        self.reader
-            .lock()
-            .unwrap()
-            .get_mut()
-            .metadata()
-            .unwrap()
-            .len()
+    pub fn index_checksums(mut self, enabled: bool) -> Self {
+        self.options.index_checksums = enabled;
+        self
     }
-    /// Sets the length of the database file directly, truncating or extending it as necessary.
+    /// Keeps at most `max_entries` index entries in memory, plus the ones touched since the
+    /// last index write, for databases with more keys than fit in memory. The index file is
+    /// written sorted by key and every 64th key is kept in memory with its offset, so the
+    /// entry of any other key is read from the file on demand. Once there are more entries in
+    /// memory, the ones not recently inserted, read or removed are dropped at the next operation
+    /// on a key after the index is written.
+    ///
+    /// Operations on a single key, (`.len()`) and (`.is_empty()`) see every entry, as do
+    /// the ones taking `&mut self` that visit all entries (e.g. (`.shrink()`), (`.iter()`),
+    /// (`.map_values()`) or (`.repair()`)): they load the whole index first, see
+    /// (`.load_index()`). The ones taking `&self`, like (`.keys()`), (`.list()`),
+    /// (`.verify()`) or (`.snapshot()`), only see the entries in memory.
+    ///
+    /// Free space between values isn't reused while entries are offloaded, new values are
+    /// appended until (`.shrink()`) compacts the file. Opening an index file that isn't sorted
+    /// by key loads it once and writes it sorted. Can't be combined with (`.single_file()`),
+    /// (`.binary_index()`), (`.background_index_writes()`), (`.amortized_compaction()`) or
+    /// (`.auto_shrink()`), opening fails with an `InvalidInput` error.
     /// # Example
     /// ```
-    /// let mut db = mu_db::DataBase::new("./test.db");
-    /// db.clear_all();
-    /// assert!(db.is_buf_empty());
-    /// assert_eq!(db.buf_len(), 0);
-    /// db.insert("key", "value");
-    /// assert_eq!(db.buf_len(), 5);
-    /// assert!(!db.is_buf_empty());
-    /// db.set_buf_len(0);
-    /// assert_eq!(db.buf_len(), 0);
-    /// assert!(db.is_buf_empty());
+    /// let mut db = mu_db::DataBase::builder("./test_max_index_entries.db")
+    ///     .max_index_entries(8)
+    ///     .open()
+    ///     .unwrap();
+    /// db.clear_all().unwrap();
+    /// for i in 0..200 {
+    ///     db.insert(&format!("k{:03}", i), &i.to_string());
+    /// }
+    /// assert_eq!(db.len(), 200);
+    /// assert!(db.keys().count() <= 9);
+    /// assert_eq!(db.get("k007"), Some("7".to_string()));
+    /// assert_eq!(db.remove("k150").unwrap(), Some("150".to_string()));
+    /// assert_eq!(db.get("k150"), None);
+    /// assert_eq!(db.len(), 199);
+    /// drop(db);
+    ///
+    /// // the sorted index file opens without the option too:
+    /// let mut db = mu_db::DataBase::new("./test_max_index_entries.db").unwrap();
+    /// assert_eq!(db.len(), 199);
+    /// assert_eq!(db.get("k199"), Some("199".to_string()));
+    /// drop(db);
+    ///
+    /// let mut db = mu_db::DataBase::builder("./test_max_index_entries.db")
+    ///     .max_index_entries(8)
+    ///     .open()
+    ///     .unwrap();
+    /// assert_eq!(db.keys().count(), 0);
+    /// assert_eq!(db.get("k123"), Some("123".to_string()));
+    /// assert!(db.contains_key("k000"));
+    /// assert!(!db.contains_key("k150"));
     /// ```
-    pub fn set_buf_len(&mut self, len: u64) {
-        let mut binding_r = self.reader.lock().unwrap();
-        let mut binding_w = self.writer.lock().unwrap();
-        let r = binding_r.get_mut();
-        let w = binding_w.get_mut();
-        r.seek(SeekFrom::Start(0)).unwrap();
-        w.seek(SeekFrom::Start(0)).unwrap();
-        r.set_len(len).unwrap();
-        w.set_len(len).unwrap();
+    /// Options that need every entry in memory are rejected:
+    /// ```
+    /// let err = mu_db::DataBase::builder("./test_max_index_entries_single.db")
+    ///     .max_index_entries(8)
+    ///     .single_file(true)
+    ///     .open()
+    ///     .err()
+    ///     .unwrap();
+    /// assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+    /// ```
+    pub fn max_index_entries(mut self, max_entries: usize) -> Self {
+        self.options.max_index_entries = Some(max_entries);
+        self
+    }
+    /// Opens the database with the configured options, see [DataBase::new]. Fails like it,
+    /// or with a [VersionMismatch] for a file written by another version of muDB.
+    pub fn open(self) -> Result<DataBase> {
+        DataBase::open_with(&self.path, self.options)
+    }
+    /// Same as (`DataBase::open_and_repair()`) with the configured options.
+    /// # Example
+    /// ```
+    /// let mut db = mu_db::DataBase::builder("./test_builder_open_and_repair.db")
+    ///     .single_file(true)
+    ///     .open()
+    ///     .unwrap();
+    /// db.clear_all().unwrap();
+    /// db.insert("k1", "hello");
+    /// db.insert("k2", "world");
+    /// drop(db);
+    ///
+    /// let (mut db, report) = mu_db::DataBase::builder("./test_builder_open_and_repair.db")
+    ///     .single_file(true)
+    ///     .open_and_repair()
+    ///     .unwrap();
+    /// assert!(report.issues.is_empty());
+    /// assert_eq!(db.get("k2"), Some("world".to_string()));
+    /// ```
+    pub fn open_and_repair(self) -> Result<(DataBase, RepairReport)> {
+        DataBase::open_and_repair_with(&self.path, self.options)
     }
 }
 
-impl Index {
-    pub fn new(path: &str) -> Self {
-        let mut index_file = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true)
-            .open(path)
-            .unwrap();
-        let mut index_string = String::new();
-        index_file.read_to_string(&mut index_string).unwrap();
-        let entries = Index::parse_index(index_string);
-        Index {
-            entries,
-            writer: Arc::new(Mutex::new(BufWriter::new(index_file))),
+#[derive(Clone)]
+pub struct Index {
+    entries: Vec<IndexEntry>,
+    tags: HashMap<String, HashSet<String>>,
+    /// Positions in `entries` sorted by key, for [KeyLookup::BinarySearch].
+    key_order: Option<Vec<usize>>,
+    /// Positions in `entries` by key hash, for [KeyLookup::Hash].
+    key_hashes: Option<HashMap<u64, Vec<usize>, BuildHasherDefault<KeyHasher>>>,
+    shrink_tail: ShrinkTailPolicy,
+    io_retries: usize,
+    /// Length of the values in the db file when the index is stored after them, see
+    /// [Options::single_file].
+    embedded_at: Option<u64>,
+    coalesce: Option<IndexWriteCoalescing>,
+    /// Bytes at the start of the db file entries aren't placed in.
+    reserved_prefix: usize,
+    /// Free ranges between entries (start to length) in offset order, `None` until the next
+    /// allocation rebuilds it.
+    free: Option<BTreeMap<usize, usize>>,
+    /// Mutations not written to the index file yet.
+    pending_writes: usize,
+    /// When the index file was last written or read.
+    last_write: Instant,
+    writes: u64,
+    /// Queue of the index writer thread, see [Options::background_index_writes].
+    background: Option<mpsc::Sender<IndexJob>>,
+    /// The index file is in the binary format, see [Options::binary_index].
+    binary: bool,
+    /// Every entry is written with a checksum, see [Options::index_checksums].
+    checksums: bool,
+    /// Entries kept out of memory, see [Options::max_index_entries].
+    offload: Option<Offload>,
+    writer: Arc<Mutex<BufWriter<File>>>,
+    path: PathBuf,
+}
+
+/// Number of index file lines per key kept in memory by [Options::max_index_entries].
+const OFFLOAD_FENCE_STRIDE: usize = 64;
+
+/// State of an index whose entries are partly kept in the index file, see
+/// [Options::max_index_entries]. The index file is sorted by key and holds every entry as of
+/// the last index write, `Index::entries` shadows the entries it holds.
+#[derive(Clone)]
+struct Offload {
+    max_entries: usize,
+    /// Reads the entries that aren't in memory.
+    reader: Arc<Mutex<BufReader<File>>>,
+    /// Every [OFFLOAD_FENCE_STRIDE]th key of the index file with the offset of its line.
+    fences: Vec<(String, u64)>,
+    /// Number of entries only in the index file.
+    count: usize,
+    /// End of the furthest range in the index file, new values are placed after it.
+    end: usize,
+    /// Keys removed since the last index write, their lines are skipped.
+    removed: HashSet<String>,
+    /// Keys used most recently, kept in memory when entries are dropped.
+    recent: VecDeque<String>,
+    /// Entries changed without an index write yet, e.g. in the middle of a batch. Entries
+    /// are only dropped from memory once they are written.
+    dirty: bool,
+    /// Every entry is in memory, see (`.load_all()`).
+    complete: bool,
+}
+
+/// Work for the index writer thread, see [Options::background_index_writes].
+enum IndexJob {
+    /// Replace the index file with this content.
+    Write(Vec<u8>),
+    /// Reply once the writes queued before are done, with the first error since the last reply.
+    Wait(mpsc::Sender<Result<()>>),
+}
+
+/// Work for the data writer thread, see [Options::background_data_writes].
+enum DataJob {
+    /// Write the bytes at the offset, then flush unless fewer than the given number of bytes
+    /// are buffered.
+    Write(u64, Vec<u8>, usize),
+    /// Reply once the writes queued before are done, with the first error since the last reply.
+    Wait(mpsc::Sender<Result<()>>),
+}
+
+/// Position in the sorted key listing of (`.list()`), the last key of the previous page.
+///
+/// Resuming continues after that key even if it was removed since, so pages stay stable
+/// across inserts and removes.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Cursor {
+    last_key: String,
+}
+
+impl Cursor {
+    /// Returns a cursor that resumes listing after `key`, e.g. one restored from a UI.
+    pub fn after(key: &str) -> Self {
+        Cursor {
+            last_key: key.to_string(),
         }
     }
-    pub fn is_empty(&self) -> bool {
-        self.entries.is_empty()
+    /// Returns the key the listing resumes after.
+    pub fn last_key(&self) -> &str {
+        &self.last_key
     }
-    pub fn insert_entry(&mut self, entry_size: usize, key: &str) -> IndexEntry {
-        // get entry if exists with index:
-        let mut old_entry = (0, None);
-        for (i, entry) in self.entries.iter().enumerate() {
-            if entry.key == key {
-                old_entry = (i, Some(entry.clone()));
-                break;
-            }
-        }
+}
 
-        match old_entry.1 {
-            Some(old) => {
-                if old.size() < entry_size {
-                    self.entries.remove(old_entry.0);
-                    return self.alloc_entry(entry_size, key);
-                } else {
-                    let entry = IndexEntry {
-                        key: key.to_string(),
-                        range: old.range.start..old.range.start + entry_size,
-                    };
-                    self.entries[old_entry.0] = entry.clone();
-                    self.write_index();
-                    return entry;
-                }
-            }
-            None => return self.alloc_entry(entry_size, key),
+/// Read-only view of a [DataBase] index at the time of (`.snapshot()`).
+///
+/// Values are read from the live db file, so a snapshot stays valid only while the bytes
+/// it points to aren't rewritten: an overwrite that fits in the old range, a new value
+/// placed in a freed gap, or (`.shrink()`) make reads of the affected keys return the new
+/// bytes. Inserts of values larger than the old ones and removes don't affect it.
+pub struct Snapshot {
+    entries: Vec<IndexEntry>,
+    reader: Arc<Mutex<BufReader<File>>>,
+    writer: Arc<Mutex<BufWriter<File>>>,
+    codec: Option<Arc<dyn ValueCodec>>,
+    /// Queue of the data writer thread, waited on before reading.
+    background: Option<mpsc::Sender<DataJob>>,
+}
+
+impl Snapshot {
+    /// Retrieves the value `key` had when the snapshot was taken.
+    pub fn get(&self, key: &str) -> Option<String> {
+        let e = self.entries.iter().find(|e| e.key == key)?;
+        if let Some(value) = &e.inline {
+            return Some(value.clone());
+        }
+        wait_data_writer(&self.background).unwrap();
+        self.writer.lock().unwrap().flush().unwrap();
+        let mut v = vec![0; e.size()];
+        let mut br = self.reader.lock().unwrap();
+        br.seek(SeekFrom::Start(e.range.start as u64)).unwrap();
+        br.read_exact(&mut v).unwrap();
+        if let Some(codec) = &self.codec {
+            v = codec.decode(&v).unwrap();
         }
+        Some(String::from_utf8_lossy(&v).into())
     }
-    pub fn alloc_entry(&mut self, entry_size: usize, key: &str) -> IndexEntry {
-        // find a empty range that new entry will fit then allocate:
-        if !self.is_empty() {
-            if self.entries[0].range.start >= entry_size {
-                let entry = IndexEntry {
-                    key: key.to_string(),
-                    range: 0..entry_size,
-                };
-                self.entries.insert(0, entry.clone());
-                self.write_index();
-                return entry;
+    /// Returns `true` if `key` existed when the snapshot was taken.
+    pub fn contains_key(&self, key: &str) -> bool {
+        self.entries.iter().any(|e| e.key == key)
+    }
+    /// Returns the number of keys in the snapshot.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+/// Iterator over the keys and values of a database, returned by (`.iter()`).
+///
+/// It holds the reader lock until it's dropped, so a [Snapshot] of the same database blocks
+/// meanwhile. Values next to each other in the db file are read through the reader's buffer
+/// without seeking.
+pub struct Iter<'a> {
+    entries: std::vec::IntoIter<IndexEntry>,
+    reader: MutexGuard<'a, BufReader<File>>,
+    /// Offset of the reader after the last value, `None` before the first one.
+    pos: Option<u64>,
+    codec: Option<&'a dyn ValueCodec>,
+}
+
+impl Iterator for Iter<'_> {
+    type Item = (String, String);
+    fn next(&mut self) -> Option<Self::Item> {
+        let e = self.entries.next()?;
+        if let Some(value) = e.inline {
+            return Some((e.key, value));
+        }
+        let start = e.range.start as u64;
+        match self.pos {
+            Some(pos) => self.reader.seek_relative(start as i64 - pos as i64),
+            None => self.reader.seek(SeekFrom::Start(start)).map(|_| ()),
+        }
+        .unwrap();
+        let mut v = vec![0; e.size()];
+        self.reader.read_exact(&mut v).unwrap();
+        self.pos = Some(start + v.len() as u64);
+        if let Some(codec) = self.codec {
+            v = codec.decode(&v).unwrap();
+        }
+        Some((e.key, String::from_utf8_lossy(&v).into()))
+    }
+}
+
+/// Read-only database opened from a (`.freeze()`) stream with (`DataBase::open_frozen()`).
+///
+/// Keys are looked up with a binary search. Values are returned as stored, a [ValueCodec]
+/// of the frozen database isn't applied.
+pub struct FrozenDataBase<R> {
+    /// Sorted by key.
+    entries: Vec<IndexEntry>,
+    reader: R,
+}
+
+impl<R: Read + Seek> FrozenDataBase<R> {
+    /// Retrieves the value of `key`.
+    pub fn get(&mut self, key: &str) -> Result<Option<String>> {
+        let Ok(i) = self.entries.binary_search_by(|e| e.key.as_str().cmp(key)) else {
+            return Ok(None);
+        };
+        let e = &self.entries[i];
+        if let Some(value) = &e.inline {
+            return Ok(Some(value.clone()));
+        }
+        let mut v = vec![0; e.size()];
+        self.reader.seek(SeekFrom::Start(e.range.start as u64))?;
+        self.reader.read_exact(&mut v)?;
+        Ok(Some(String::from_utf8_lossy(&v).into()))
+    }
+    pub fn contains_key(&self, key: &str) -> bool {
+        self.entries
+            .binary_search_by(|e| e.key.as_str().cmp(key))
+            .is_ok()
+    }
+    /// Returns the keys in sorted order.
+    pub fn keys(&self) -> impl Iterator<Item = &str> {
+        self.entries.iter().map(|e| e.key.as_str())
+    }
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+/// Cheaply clonable handle sharing one [DataBase] between threads, with many concurrent
+/// readers and a single writer.
+///
+/// Reads take `&self`, writes go through the guard returned by (`.write()`) which blocks
+/// readers while held.
+/// # Example
+/// ```
+/// let mut db = mu_db::DataBase::new("./test_shared.db").unwrap();
+/// db.clear_all().unwrap();
+/// let shared = mu_db::SharedDataBase::new(db);
+/// shared.write().insert("key", "value");
+///
+/// let readers: Vec<_> = (0..4)
+///     .map(|_| {
+///         let shared = shared.clone();
+///         std::thread::spawn(move || shared.get("key"))
+///     })
+///     .collect();
+/// for r in readers {
+///     assert_eq!(r.join().unwrap(), Some("value".to_string()));
+/// }
+/// assert!(shared.contains_key("key"));
+/// assert_eq!(shared.keys(), vec!["key"]);
+/// ```
+#[derive(Clone)]
+pub struct SharedDataBase {
+    inner: Arc<RwLock<DataBase>>,
+}
+
+impl SharedDataBase {
+    pub fn new(db: DataBase) -> Self {
+        SharedDataBase {
+            inner: Arc::new(RwLock::new(db)),
+        }
+    }
+    /// Retrieves the value of `key`, see [DataBase::get].
+    pub fn get(&self, key: &str) -> Option<String> {
+        let db = self.read();
+        let e = db.index.get_entry(key)?;
+        Some(db.fetch_value(&e).unwrap())
+    }
+    /// Returns `true` if `key` exists, see [DataBase::contains_key].
+    pub fn contains_key(&self, key: &str) -> bool {
+        self.read().contains_key(key)
+    }
+    /// Returns all keys in file offset order.
+    pub fn keys(&self) -> Vec<String> {
+        self.read().keys().map(String::from).collect()
+    }
+    /// Locks the database for reading, blocking while a writer holds it.
+    pub fn read(&self) -> RwLockReadGuard<'_, DataBase> {
+        self.inner.read().unwrap()
+    }
+    /// Locks the database for writing, blocking until all readers are done.
+    pub fn write(&self) -> RwLockWriteGuard<'_, DataBase> {
+        self.inner.write().unwrap()
+    }
+}
+
+/// How [Index] finds the entry of a key.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum KeyLookup {
+    /// Scan all entries, O(n) and no extra memory.
+    Linear,
+    /// Binary search over a key-sorted list of entry positions, O(log n) and one `usize`
+    /// per entry. Inserts and removes stay O(n) as with `Linear`.
+    BinarySearch,
+    /// Hash map from key hash (see [DataBase::hash_key]) to entry positions, O(1) and about
+    /// two `u64` per entry. Inserts and removes of the last entry in the file are O(1), others
+    /// stay O(n). The default.
+    #[default]
+    Hash,
+}
+
+/// Passes through the `u64` key hashes of [KeyLookup::Hash] instead of hashing them again.
+#[derive(Default)]
+struct KeyHasher(u64);
+
+impl Hasher for KeyHasher {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+    fn write(&mut self, bytes: &[u8]) {
+        for b in bytes {
+            self.0 = (self.0 << 8) | *b as u64;
+        }
+    }
+    fn write_u64(&mut self, n: u64) {
+        self.0 = n;
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct IndexEntry {
+    key: String,
+    range: Range<usize>,
+    tags: Vec<String>,
+    version: u64,
+    /// Bytes after `range` kept for this entry by [ShrinkTailPolicy::Keep].
+    reserved: usize,
+    content_type: Option<String>,
+    /// The value itself if it's stored in the index, see (`DataBaseBuilder::inline_threshold()`).
+    inline: Option<String>,
+}
+
+/// Which entry [Index::set_all_entries_with] keeps for a key that appears more than once.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DuplicateKeyPolicy {
+    KeepFirst,
+    KeepLast,
+    /// Reject the entries.
+    Error,
+}
+
+/// How (`.merge_from()`) resolves a key present in both databases.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConflictPolicy {
+    KeepSelf,
+    TakeOther,
+    /// Reject the merge.
+    Error,
+}
+
+/// Location of a value in the db file, returned by (`.head()`).
+///
+/// `size` is the length of the value. A value stored in the index (see
+/// (`DataBaseBuilder::inline_threshold()`)) has nothing stored at `start`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Head {
+    pub start: usize,
+    pub size: usize,
+}
+
+/// What happens to the freed tail when a value is overwritten with a smaller one.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ShrinkTailPolicy {
+    /// The tail becomes a gap that the next insert that fits can reuse.
+    #[default]
+    Free,
+    /// The tail stays reserved for the entry, so it can grow back in place without being
+    /// relocated. Only (`.shrink()`) reclaims it.
+    Keep,
+}
+
+impl DataBase {
+    /// Creates a new instance of the database or uses the existing db file,
+    /// at the given path.
+    /// # Example
+    /// ```
+    /// let db = mu_db::DataBase::new("./test.db").unwrap();
+    /// ```
+    /// Generates (`./test.db`) and (`./index_test.db`) if doesn't exist.
+    ///
+    /// Returns an error if `path` (or the index path) is a directory, the files can't be
+    /// opened or the index can't be parsed.
+    /// ```
+    /// std::fs::create_dir_all("./test_dir.db").unwrap();
+    /// let err = mu_db::DataBase::new("./test_dir.db").err().unwrap();
+    /// assert_eq!(err.to_string(), "path is a directory: ./test_dir.db");
+    /// ```
+    pub fn new(path: &str) -> Result<Self> {
+        DataBase::open_with(path, Options::default())
+    }
+    /// Same as (`DataBase::new()`) but panics on errors.
+    /// # Example
+    /// ```
+    /// let mut db = mu_db::DataBase::new_or_panic("./test_new_or_panic.db");
+    /// db.insert("key", "value");
+    /// ```
+    pub fn new_or_panic(path: &str) -> Self {
+        DataBase::new(path).unwrap_or_else(|e| panic!("{}", e))
+    }
+    /// Same as (`DataBase::new()`) with room for `index_capacity` index entries, see
+    /// (`DataBaseBuilder::index_capacity()`).
+    /// # Example
+    /// ```
+    /// let mut db =
+    ///     mu_db::DataBase::open_with_capacity("./test_open_with_capacity.db", 1000).unwrap();
+    /// let before = db.index_memory_usage();
+    /// db.insert("key", "value");
+    /// assert!(db.index_memory_usage() - before < 100);
+    /// ```
+    pub fn open_with_capacity(path: &str, index_capacity: usize) -> Result<Self> {
+        DataBase::builder(path)
+            .index_capacity(index_capacity)
+            .open()
+    }
+    /// Returns a [DataBaseBuilder] to open the database at `path` with custom [Options].
+    pub fn builder(path: &str) -> DataBaseBuilder {
+        DataBaseBuilder {
+            path: path.to_string(),
+            options: Options::default(),
+        }
+    }
+    /// Opens the database at `path` like (`DataBase::new()`) but tolerates a damaged index:
+    /// index lines that can't be parsed are dropped instead of failing, then
+    /// (`.repair()`) fixes the issues found by (`.verify()`).
+    /// # Example
+    /// ```
+    /// let mut db = mu_db::DataBase::new("./test_open_and_repair.db").unwrap();
+    /// db.clear_all().unwrap();
+    /// db.insert("k1", "hello");
+    /// db.insert("k2", "world");
+    /// drop(db);
+    /// // a damaged index: a garbage line, an overlap and a range past the end of the file
+    /// std::fs::write(
+    ///     "./index_test_open_and_repair.db",
+    ///     "k1=0_5\ngarbage\nk2=5_10\nk3=8_10\nk4=10_20\n",
+    /// )
+    /// .unwrap();
+    ///
+    /// let (mut db, report) = mu_db::DataBase::open_and_repair("./test_open_and_repair.db").unwrap();
+    /// assert_eq!(report.unparsed_lines, vec![2]);
+    /// assert_eq!(report.dropped, vec!["k4".to_string(), "k3".to_string()]);
+    /// assert_eq!(db.get("k2"), Some("world".to_string()));
+    /// assert!(db.verify().is_empty());
+    /// ```
+    /// Lines are kept as they are, an inline value ending in `\r` survives a repair:
+    /// ```
+    /// let mut db = mu_db::DataBase::builder("./test_open_and_repair_inline.db")
+    ///     .inline_threshold(8)
+    ///     .open()
+    ///     .unwrap();
+    /// db.clear_all().unwrap();
+    /// db.insert("k", "a \r");
+    /// drop(db);
+    /// let index = std::fs::read_to_string("./index_test_open_and_repair_inline.db").unwrap();
+    /// std::fs::write("./index_test_open_and_repair_inline.db", index + "garbage\n").unwrap();
+    ///
+    /// let (mut db, report) = mu_db::DataBase::builder("./test_open_and_repair_inline.db")
+    ///     .inline_threshold(8)
+    ///     .open_and_repair()
+    ///     .unwrap();
+    /// assert_eq!(report.unparsed_lines, vec![2]);
+    /// assert_eq!(db.get("k"), Some("a \r".to_string()));
+    /// ```
+    pub fn open_and_repair(path: &str) -> Result<(DataBase, RepairReport)> {
+        DataBase::builder(path).open_and_repair()
+    }
+    fn open_and_repair_with(path: &str, options: Options) -> Result<(DataBase, RepairReport)> {
+        let index_path = index_path_for(path);
+        let mut unparsed_lines = Vec::new();
+        // a binary or embedded index has no lines to skip, it's either parsed whole or not at all:
+        let content = if options.single_file {
+            None
+        } else {
+            std::fs::read_to_string(&index_path).ok()
+        };
+        if let Some(content) = content.filter(|c| {
+            let header = c.as_bytes().get(..8);
+            !c.is_empty()
+                && header
+                    .and_then(|h| footer_version(h, BINARY_INDEX_MAGIC))
+                    .is_none()
+        }) {
+            let mut kept = String::new();
+            // split like the parser, so trailing spaces and `\r` stay part of a line:
+            let lines = content.strip_suffix('\n').unwrap_or(&content).split('\n');
+            for (i, line) in lines.enumerate() {
+                let line = format!("{}\n", line);
+                if Index::parse_index(line.clone()).is_ok() {
+                    kept.push_str(&line);
+                } else {
+                    unparsed_lines.push(i + 1);
+                }
+            }
+            if !unparsed_lines.is_empty() {
+                std::fs::write(&index_path, kept)?;
+            }
+        }
+        let mut db = DataBase::open_with(path, options)?;
+        let mut report = db.repair();
+        report.unparsed_lines = unparsed_lines;
+        Ok((db, report))
+    }
+    /// Returns the on-disk format version of the db file at `path` without opening it, so
+    /// tools can check it against [FORMAT_VERSION] first. Opening a file with another version
+    /// fails with a [VersionMismatch] error.
+    ///
+    /// Single file databases (`DataBaseBuilder::single_file()`) record their version at the end
+    /// of the db file and binary index files (`DataBaseBuilder::binary_index()`) in their
+    /// header, any other database is reported as version 1, the text index format.
+    /// # Example
+    /// ```
+    /// // the end of this test corrupts the file, so start from a fresh one
+    /// let _ = std::fs::remove_file("./test_format_version.db");
+    /// let mut db = mu_db::DataBase::builder("./test_format_version.db")
+    ///     .single_file(true)
+    ///     .open()
+    ///     .unwrap();
+    /// db.clear_all().unwrap();
+    /// db.insert("k", "v");
+    /// drop(db);
+    /// let version = mu_db::DataBase::format_version("./test_format_version.db").unwrap();
+    /// assert_eq!(version, mu_db::FORMAT_VERSION);
+    ///
+    /// let mut file = std::fs::read("./test_format_version.db").unwrap();
+    /// *file.last_mut().unwrap() = b'2';
+    /// std::fs::write("./test_format_version.db", file).unwrap();
+    /// assert_eq!(mu_db::DataBase::format_version("./test_format_version.db").unwrap(), 2);
+    /// let err = mu_db::DataBase::builder("./test_format_version.db")
+    ///     .single_file(true)
+    ///     .open()
+    ///     .err()
+    ///     .unwrap();
+    /// let mismatch = err.get_ref().unwrap().downcast_ref::<mu_db::VersionMismatch>();
+    /// assert_eq!(mismatch.unwrap().found, 2);
+    /// ```
+    /// The header of a binary index file counts as well:
+    /// ```
+    /// let mut db = mu_db::DataBase::builder("./test_format_version_binary.db")
+    ///     .binary_index(true)
+    ///     .open()
+    ///     .unwrap();
+    /// db.clear_all().unwrap();
+    /// db.insert("k", "v");
+    /// drop(db);
+    /// let version = mu_db::DataBase::format_version("./test_format_version_binary.db").unwrap();
+    /// assert_eq!(version, mu_db::FORMAT_VERSION);
+    ///
+    /// std::fs::write("./test_format_version_binary2.db", "v").unwrap();
+    /// std::fs::write("./index_test_format_version_binary2.db", "muDBbin2").unwrap();
+    /// let version = mu_db::DataBase::format_version("./test_format_version_binary2.db").unwrap();
+    /// assert_eq!(version, 2);
+    /// let err = mu_db::DataBase::new("./test_format_version_binary2.db").err().unwrap();
+    /// let mismatch = err.get_ref().unwrap().downcast_ref::<mu_db::VersionMismatch>();
+    /// assert_eq!(mismatch.unwrap().found, 2);
+    /// ```
+    pub fn format_version(path: &str) -> Result<u32> {
+        let mut file = File::open(path)?;
+        let len = file.metadata()?.len();
+        if len >= 16 {
+            let mut magic = [0; 8];
+            file.seek(SeekFrom::Start(len - 8))?;
+            file.read_exact(&mut magic)?;
+            if let Some(version) = footer_version(&magic, EMBEDDED_INDEX_MAGIC) {
+                return Ok(version);
+            }
+        }
+        let mut index = match File::open(index_path_for(path)) {
+            Ok(index) => index,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(1),
+            Err(e) => return Err(e),
+        };
+        let mut header = Vec::with_capacity(8);
+        (&mut index).take(8).read_to_end(&mut header)?;
+        Ok(footer_version(&header, BINARY_INDEX_MAGIC).unwrap_or(1))
+    }
+    /// Returns the path of the db file.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+    /// Returns the path of the index file.
+    pub fn index_path(&self) -> &Path {
+        self.index.path()
+    }
+    /// Returns the options the database was opened with.
+    pub fn options(&self) -> &Options {
+        &self.options
+    }
+    fn open_with(path: &str, options: Options) -> Result<Self> {
+        if options.max_index_entries.is_some()
+            && (options.single_file
+                || options.binary_index
+                || options.background_index_writes
+                || options.compaction_budget.is_some()
+                || options.auto_shrink.is_some())
+        {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "max_index_entries can't be combined with single_file, binary_index, \
+                 background_index_writes, amortized_compaction or auto_shrink",
+            ));
+        }
+        let file = open_file(path)?;
+        let file_clone = file.try_clone()?;
+        let data_len = file.metadata()?.len();
+
+        let mut index = if options.single_file {
+            Index::open_embedded(path, options.index_capacity)?
+        } else if let Some(max_entries) = options.max_index_entries {
+            Index::open_offloaded(&index_path_for(path), max_entries)?
+        } else {
+            Index::open(&index_path_for(path), options.index_capacity)?
+        };
+        index.set_key_lookup(options.key_lookup);
+        index.set_shrink_tail_policy(options.shrink_tail);
+        index.set_io_retries(options.io_retries);
+        index.set_coalescing(options.coalesce_index_writes);
+        index.set_reserved_prefix(options.reserve_prefix);
+        index.set_background_writes(options.background_index_writes)?;
+        if options.binary_index {
+            index.set_binary(true);
+        }
+        index.set_checksums(options.index_checksums);
+
+        let writer = Arc::new(Mutex::new(data_writer(file_clone, &options)));
+        let background = (options.background_data_writes && !options.single_file)
+            .then(|| spawn_data_writer(writer.clone(), options.io_retries));
+
+        Ok(DataBase {
+            index,
+            reader: Arc::new(Mutex::new(BufReader::new(file))),
+            writer,
+            options,
+            auto_flush: true,
+            last_inserted: None,
+            path: PathBuf::from(path),
+            data_len,
+            background,
+        })
+    }
+
+    /// Inserts a key-value pair into the database, replacing old value if key exists.
+    /// # Example
+    ///
+    /// ```
+    /// let mut db = mu_db::DataBase::new("./test.db").unwrap();
+    /// db.insert("key", "before");
+    /// db.insert("key", "after");
+    /// assert_eq!(db.get("key"), Some("after".to_string()));
+    /// ```
+    pub fn insert(&mut self, key: &str, value: &str) {
+        self.insert_bytes(key, value.as_bytes())
+    }
+    /// Same as (`.insert()`) but returns an `InvalidInput` error for a key that isn't valid
+    /// (see (`DataBase::is_valid_key()`)) and the error of a failed write, instead of
+    /// panicking.
+    /// # Example
+    /// ```
+    /// let mut db = mu_db::DataBase::new("./test_try_insert.db").unwrap();
+    /// db.clear_all().unwrap();
+    /// db.try_insert("a=b\nc", "value").unwrap();
+    /// assert_eq!(db.get("a=b\nc"), Some("value".to_string()));
+    ///
+    /// let err = db.try_insert("", "value").unwrap_err();
+    /// assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+    /// assert_eq!(db.len(), 1);
+    /// ```
+    pub fn try_insert(&mut self, key: &str, value: &str) -> Result<()> {
+        if !DataBase::is_valid_key(key) {
+            return Err(invalid_key(key));
+        }
+        self.put_bytes(key, value.as_bytes())?;
+        self.compact_step()
+    }
+    /// Same as (`.insert()`), returns the previous value if the key existed, like
+    /// `HashMap::insert`. The previous value is read before it's overwritten.
+    ///
+    /// Returns an `InvalidInput` error for a key (`.is_valid_key()`) rejects, and any error
+    /// reading the previous value or writing the new one; nothing is written if the previous
+    /// value can't be read.
+    /// # Example
+    /// ```
+    /// let mut db = mu_db::DataBase::new("./test_insert_replace.db").unwrap();
+    /// db.clear_all().unwrap();
+    /// assert_eq!(db.insert_replace("key", "before").unwrap(), None);
+    /// assert_eq!(db.insert_replace("key", "after").unwrap(), Some("before".to_string()));
+    /// assert_eq!(db.get("key"), Some("after".to_string()));
+    ///
+    /// // a value that fits the old slot is overwritten in place:
+    /// let start = db.head("key").unwrap().start;
+    /// assert_eq!(db.insert_replace("key", "new").unwrap(), Some("after".to_string()));
+    /// assert_eq!(db.head("key").unwrap().start, start);
+    /// ```
+    pub fn insert_replace(&mut self, key: &str, value: &str) -> Result<Option<String>> {
+        if !DataBase::is_valid_key(key) {
+            return Err(invalid_key(key));
+        }
+        self.index.promote(key)?;
+        let hash = self.index.hash_of(key);
+        let pos = self.index.position_hashed(key, hash);
+        let old = match pos {
+            Some(p) => Some(self.fetch_value(&self.index.entries[p])?),
+            None => None,
+        };
+        if let Some(value) = self.inline_str(value.as_bytes()) {
+            let i = self.index.place_entry_found(pos, 0, key, hash);
+            self.index.entries[i].inline = Some(value.to_string());
+            self.index.try_write_index()?;
+        } else {
+            let value = self.encode(value.as_bytes());
+            let i = self.index.place_entry_found(pos, value.len(), key, hash);
+            self.index.try_write_index()?;
+            let start = self.index.entries[i].range.start;
+            self.write_bytes(start as u64, &value)?;
+        }
+        self.last_inserted = Some(key.to_string());
+        self.compact_step()?;
+        Ok(old)
+    }
+    /// Inserts all `pairs` like (`.insert()`) in a loop, with a single index write at the end.
+    /// # Example
+    /// ```
+    /// let mut db = mu_db::DataBase::new("./test_insert_many.db").unwrap();
+    /// db.clear_all().unwrap();
+    /// db.insert_many(&[("k1", "one"), ("k2", "two"), ("k1", "uno")]);
+    /// assert_eq!(db.len(), 2);
+    /// assert_eq!(db.get("k1"), Some("uno".to_string()));
+    /// assert_eq!(db.get("k2"), Some("two".to_string()));
+    ///
+    /// // new keys of the same batch fill a gap one after the other:
+    /// db.clear_all().unwrap();
+    /// db.insert_many(&[("a", "aaaa"), ("b", "bbbb"), ("c", "cccc")]);
+    /// db.remove("a").unwrap();
+    /// db.remove("b").unwrap();
+    /// db.insert_many(&[("x", "xxx"), ("y", "yyy")]);
+    /// assert_eq!(db.head("x").unwrap().start, 0);
+    /// assert_eq!(db.head("y").unwrap().start, 3);
+    /// assert_eq!(db.buf_len(), 12);
+    /// ```
+    pub fn insert_many(&mut self, pairs: &[(&str, &str)]) {
+        for &(key, value) in pairs {
+            if let Some(value) = self.inline_str(value.as_bytes()) {
+                self.index.place_inline_entry(key, value);
+            } else {
+                let value = self.encode(value.as_bytes());
+                let index_entry = self.index.place_entry(value.len(), key);
+                self.write_bytes(index_entry.range.start.try_into().unwrap(), &value)
+                    .unwrap();
+            }
+            self.last_inserted = Some(key.to_string());
+        }
+        self.index.write_index();
+        self.compact_step_or_log();
+    }
+    /// Inserts all `pairs` with a single index write at the end, a key given more than once
+    /// gets its last value.
+    ///
+    /// The batch is atomic: values of new keys go into free space (gaps left by removed values
+    /// are reused, one after the other) and values of existing keys are appended after all
+    /// data, so nothing the index file points to is overwritten before the index is written.
+    /// A crash before that leaves the database as it was; a failed write rolls back the
+    /// in-memory index and returns the error. An invalid key (see
+    /// (`DataBase::is_valid_key()`)) fails with an `InvalidInput` error before anything is
+    /// written. In single file mode (`DataBaseBuilder::single_file()`) values appended past
+    /// the index block overwrite it until it's written at the end.
+    /// # Example
+    /// ```
+    /// let mut db = mu_db::DataBase::new("./test_insert_batch.db").unwrap();
+    /// db.clear_all().unwrap();
+    /// db.insert_batch(&[("a", "aaaa"), ("b", "bbbb"), ("c", "cccc")]).unwrap();
+    /// db.remove("a").unwrap();
+    /// db.remove("b").unwrap();
+    ///
+    /// let writes = db.index_writes();
+    /// db.insert_batch(&[("x", "xxx"), ("c", "new"), ("y", "yyy"), ("x", "xx")]).unwrap();
+    /// assert_eq!(db.index_writes(), writes + 1);
+    /// // new keys share the gap, the existing key is appended:
+    /// assert_eq!(db.head("x").unwrap().start, 3);
+    /// assert_eq!(db.head("y").unwrap().start, 0);
+    /// assert_eq!(db.head("c").unwrap().start, 12);
+    /// assert_eq!(db.get("x"), Some("xx".to_string()));
+    /// assert_eq!(db.get("c"), Some("new".to_string()));
+    ///
+    /// let err = db.insert_batch(&[("z", "z"), ("", "empty")]).unwrap_err();
+    /// assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+    /// assert_eq!(db.get("z"), None);
+    /// ```
+    pub fn insert_batch(&mut self, pairs: &[(&str, &str)]) -> Result<()> {
+        if let Some((key, _)) = pairs.iter().find(|(key, _)| !DataBase::is_valid_key(key)) {
+            return Err(invalid_key(key));
+        }
+        let mut last = HashMap::new();
+        for (i, (key, _)) in pairs.iter().enumerate() {
+            last.insert(*key, i);
+        }
+        let (new, existing): (Vec<_>, Vec<_>) = pairs
+            .iter()
+            .enumerate()
+            .filter(|(i, (key, _))| last[key] == *i)
+            .map(|(_, pair)| *pair)
+            .partition(|(key, _)| !self.index.contains(key));
+        self.run_batch(|db, written_end| db.insert_batch_values(&new, &existing, written_end))?;
+        self.compact_step()
+    }
+    /// Places and writes the values of `insert_batch`: `new` keys first, so they only take
+    /// space that was free before the batch, then `existing` ones after all data.
+    fn insert_batch_values(
+        &mut self,
+        new: &[(&str, &str)],
+        existing: &[(&str, &str)],
+        written_end: &mut u64,
+    ) -> Result<()> {
+        for &(key, value) in new {
+            if let Some(value) = self.inline_str(value.as_bytes()) {
+                self.index.place_inline_entry(key, value);
+            } else {
+                let value = self.encode(value.as_bytes()).into_owned();
+                let entry = self.index.place_new_entry(value.len(), key);
+                *written_end = (*written_end).max(entry.range.end as u64);
+                self.write_values(entry.range.start as u64, &value)?;
+            }
+            self.last_inserted = Some(key.to_string());
+        }
+        for &(key, value) in existing {
+            if let Some(value) = self.inline_str(value.as_bytes()) {
+                self.index.place_inline_entry(key, value);
+            } else {
+                let value = self.encode(value.as_bytes()).into_owned();
+                let start = self.index.data_end();
+                self.index.append_entry(value.len(), key, start);
+                *written_end = (*written_end).max((start + value.len()) as u64);
+                self.write_values(start as u64, &value)?;
+            }
+            self.last_inserted = Some(key.to_string());
+        }
+        Ok(())
+    }
+    /// Same as (`.insert()`) for a binary value, the bytes are stored as they are.
+    /// # Example
+    /// ```
+    /// let mut db = mu_db::DataBase::new("./test_insert_bytes.db").unwrap();
+    /// db.clear_all().unwrap();
+    /// let png_header = [0x89, b'P', b'N', b'G', 0x0d, 0x0a, 0x1a, 0x0a];
+    /// db.insert_bytes("thumbnail", &png_header);
+    /// assert_eq!(db.get_bytes("thumbnail"), Some(png_header.to_vec()));
+    /// assert_eq!(db.get_bytes("missing"), None);
+    /// ```
+    pub fn insert_bytes(&mut self, key: &str, value: &[u8]) {
+        self.put_bytes(key, value).unwrap();
+        self.compact_step_or_log();
+    }
+    /// Same as (`.insert_bytes()`) without the compaction step, returning the error of a
+    /// failed write.
+    fn put_bytes(&mut self, key: &str, value: &[u8]) -> Result<()> {
+        if let Some(value) = self.inline_str(value) {
+            self.index.insert_inline_entry(key, value);
+        } else {
+            let value = self.encode(value);
+            let index_entry = self.index.insert_entry(value.len(), key);
+            self.write_bytes(index_entry.range.start as u64, &value)?;
+        }
+        self.last_inserted = Some(key.to_string());
+        Ok(())
+    }
+    /// Same as (`.insert()`) with `hash` being (`DataBase::hash_key(key)`), computed by the caller
+    /// (e.g. to pick a shard) so that [KeyLookup::Hash] doesn't hash the key again. With
+    /// other lookups it's ignored.
+    ///
+    /// The hash is only checked in debug builds: a `hash` other than (`DataBase::hash_key(key)`)
+    /// files the entry under the wrong hash, so [KeyLookup::Hash] lookups of `key` miss it and
+    /// a later insert of the same key adds a duplicate entry.
+    /// # Example
+    /// ```
+    /// use mu_db::{DataBase, KeyLookup};
+    /// let mut shards: Vec<DataBase> = (0..2)
+    ///     .map(|i| {
+    ///         DataBase::builder(&format!("./test_prehashed_{}.db", i))
+    ///             .key_lookup(KeyLookup::Hash)
+    ///             .open()
+    ///             .unwrap()
+    ///     })
+    ///     .collect();
+    /// for db in shards.iter_mut() {
+    ///     db.clear_all().unwrap();
+    /// }
+    /// for key in ["a", "b", "c", "d"] {
+    ///     let hash = DataBase::hash_key(key);
+    ///     shards[(hash % 2) as usize].insert_prehashed(key, hash, key);
+    /// }
+    /// let hash = DataBase::hash_key("c");
+    /// assert_eq!(shards[(hash % 2) as usize].get("c"), Some("c".to_string()));
+    /// ```
+    pub fn insert_prehashed(&mut self, key: &str, hash: u64, value: &str) {
+        debug_assert_eq!(hash, DataBase::hash_key(key), "hash of {:?}", key);
+        if let Some(value) = self.inline_str(value.as_bytes()) {
+            self.index.insert_inline_entry(key, value);
+        } else {
+            let value = self.encode(value.as_bytes());
+            let index_entry = self.index.insert_entry_prehashed(value.len(), key, hash);
+            self.write_bytes(index_entry.range.start.try_into().unwrap(), &value)
+                .unwrap();
+        }
+        self.last_inserted = Some(key.to_string());
+        self.compact_step_or_log();
+    }
+    /// Returns `true` if `key` is valid: it's not empty and at most [MAX_KEY_LEN] bytes
+    /// long. Separators of the index file (`=`, `,` and newlines) are escaped, so keys may
+    /// contain them. (`.try_insert()`) and (`.apply()`) fail with an `InvalidInput` error for
+    /// an invalid key, while (`.insert()`) stores any key the index file can hold, the empty
+    /// one included.
+    /// # Example
+    /// ```
+    /// use mu_db::DataBase;
+    /// assert!(DataBase::is_valid_key("user=42"));
+    /// assert!(!DataBase::is_valid_key(""));
+    /// assert!(!DataBase::is_valid_key(&"k".repeat(mu_db::MAX_KEY_LEN + 1)));
+    ///
+    /// let mut db = DataBase::new("./test_escaped_keys.db").unwrap();
+    /// db.clear_all().unwrap();
+    /// db.insert("user=42", "a");
+    /// db.insert("line\nbreak_1", "b");
+    /// drop(db);
+    /// let mut db = DataBase::new("./test_escaped_keys.db").unwrap();
+    /// assert_eq!(db.get("user=42"), Some("a".to_string()));
+    /// assert_eq!(db.get("line\nbreak_1"), Some("b".to_string()));
+    ///
+    /// db.insert("", "empty key");
+    /// assert_eq!(db.get(""), Some("empty key".to_string()));
+    /// ```
+    pub fn is_valid_key(key: &str) -> bool {
+        !key.is_empty() && key.len() <= MAX_KEY_LEN
+    }
+    /// Returns the hash [KeyLookup::Hash] uses for `key`. It's stable for the lifetime of the
+    /// process but not across Rust versions, so it shouldn't be persisted.
+    pub fn hash_key(key: &str) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        hasher.finish()
+    }
+    /// Retrieves the value associated with the given key from the database.
+    /// # Example
+    /// ```
+    /// let mut db = mu_db::DataBase::new("./test.db").unwrap();
+    /// db.insert("key", "value");
+    /// assert_eq!(db.get("key"), Some("value".to_string()));
+    /// ```
+    pub fn get(&mut self, key: &str) -> Option<String> {
+        self.get_bytes(key)
+            .map(|v| String::from_utf8_lossy(&v).into())
+    }
+    /// Returns the value of `key`, or stores the value returned by `f` and returns it if the key
+    /// is missing. `f` only runs when the key is missing.
+    /// # Example
+    /// ```
+    /// let mut db = mu_db::DataBase::new("./test_get_or_insert_with.db").unwrap();
+    /// db.clear_all().unwrap();
+    /// assert_eq!(db.get_or_insert_with("key", || "default".to_string()), "default");
+    /// assert_eq!(db.get_or_insert_with("key", || unreachable!()), "default");
+    /// assert_eq!(db.get("key"), Some("default".to_string()));
+    /// ```
+    pub fn get_or_insert_with<F: FnOnce() -> String>(&mut self, key: &str, f: F) -> String {
+        if let Some(value) = self.get(key) {
+            return value;
+        }
+        let value = f();
+        self.insert(key, &value);
+        value
+    }
+    /// Passes the value of `key` (`None` if it's missing) to `f` and stores what it returns:
+    /// `Some` replaces the value like (`.insert()`), `None` removes the key.
+    ///
+    /// A new value that fits the old slot (including a tail kept by [ShrinkTailPolicy::Keep])
+    /// is written in place. A longer one is written to a gap that fits or the end of the file,
+    /// and the old slot becomes a gap.
+    /// # Example
+    /// ```
+    /// let mut db = mu_db::DataBase::new("./test_update.db").unwrap();
+    /// db.clear_all().unwrap();
+    /// db.insert("log", "a");
+    /// db.insert("other", "b");
+    /// db.update("log", |v| Some(v.unwrap_or_default() + ",c"));
+    /// assert_eq!(db.get("log"), Some("a,c".to_string()));
+    /// // the longer value didn't fit its slot at 0 and moved after `other`:
+    /// assert_eq!(db.head("log").unwrap().start, 2);
+    ///
+    /// db.update("log", |_| None);
+    /// assert_eq!(db.get("log"), None);
+    /// db.update("new", |v| v.or(Some("created".to_string())));
+    /// assert_eq!(db.get("new"), Some("created".to_string()));
+    /// ```
+    pub fn update<F: FnOnce(Option<String>) -> Option<String>>(&mut self, key: &str, f: F) {
+        match f(self.get(key)) {
+            Some(value) => self.insert(key, &value),
+            None => {
+                self.discard(key);
+                self.compact_step_or_log();
+            }
+        }
+    }
+    /// Same as (`.get()`) for a binary value, see (`.insert_bytes()`).
+    pub fn get_bytes(&mut self, key: &str) -> Option<Vec<u8>> {
+        self.index.promote(key).unwrap();
+        self.index
+            .get_entry(key)
+            .map(|e| self.fetch_value_bytes(&e).unwrap())
+    }
+    /// Returns `true` if `key` exists. Only the index is consulted, the value isn't read.
+    /// # Example
+    /// ```
+    /// let mut db = mu_db::DataBase::new("./test_contains_key.db").unwrap();
+    /// db.clear_all().unwrap();
+    /// db.insert("key", &"large value".repeat(1000));
+    /// assert!(db.contains_key("key"));
+    /// assert!(!db.contains_key("missing"));
+    /// ```
+    pub fn contains_key(&self, key: &str) -> bool {
+        self.index.contains(key)
+    }
+    /// Returns `value` as text if it's stored in the index, see
+    /// (`DataBaseBuilder::inline_threshold()`). Binary values are always stored in the db file.
+    fn inline_str<'a>(&self, value: &'a [u8]) -> Option<&'a str> {
+        if value.len() < self.options.inline_threshold && self.options.codec.is_none() {
+            std::str::from_utf8(value).ok()
+        } else {
+            None
+        }
+    }
+    /// Applies the [ValueCodec] (if any) to a value about to be written.
+    fn encode<'a>(&self, value: &'a [u8]) -> Cow<'a, [u8]> {
+        match &self.options.codec {
+            Some(codec) => Cow::Owned(codec.encode(value)),
+            None => Cow::Borrowed(value),
+        }
+    }
+    /// Reads and decodes the value of `entry`.
+    fn fetch_value(&self, entry: &IndexEntry) -> Result<String> {
+        Ok(String::from_utf8_lossy(&self.fetch_value_bytes(entry)?).into())
+    }
+    fn fetch_value_bytes(&self, entry: &IndexEntry) -> Result<Vec<u8>> {
+        if let Some(value) = &entry.inline {
+            return Ok(value.clone().into_bytes());
+        }
+        let mut v = vec![0; entry.size()];
+        self.read_into(entry.range.start as u64, &mut v)?;
+        if let Some(codec) = &self.options.codec {
+            v = codec.decode(&v)?;
+        }
+        Ok(v)
+    }
+    /// Returns bytes `range` of the value of `key`, or `None` if the key doesn't exist.
+    ///
+    /// Only the requested bytes are read, unless a [ValueCodec] is set. Returns an
+    /// `InvalidInput` error if `range` isn't within the value.
+    /// # Example
+    /// ```
+    /// let mut db = mu_db::DataBase::new("./test_get_range_bytes.db").unwrap();
+    /// db.clear_all().unwrap();
+    /// db.insert("key", "héllo");
+    /// assert_eq!(db.get_range_bytes("key", 1..3).unwrap(), Some("é".as_bytes().to_vec()));
+    /// assert_eq!(db.get_range_bytes("key", 0..2).unwrap(), Some(b"h\xc3".to_vec()));
+    /// assert!(db.get_range_bytes("key", 4..10).is_err());
+    /// ```
+    pub fn get_range_bytes(&mut self, key: &str, range: Range<usize>) -> Result<Option<Vec<u8>>> {
+        let Some(e) = self.index.get_entry(key) else {
+            return Ok(None);
+        };
+        if self.options.codec.is_some() || e.inline.is_some() {
+            let value = self.fetch_value_bytes(&e)?;
+            return match value.get(range.clone()) {
+                Some(bytes) => Ok(Some(bytes.to_vec())),
+                None => Err(range_error(&range, value.len())),
+            };
+        }
+        if range.start > range.end || range.end > e.size() {
+            return Err(range_error(&range, e.size()));
+        }
+        let mut v = vec![0; range.len()];
+        self.read_into((e.range.start + range.start) as u64, &mut v)?;
+        Ok(Some(v))
+    }
+    /// Same as (`.get_range_bytes()`) for text values: returns an `InvalidData` error instead
+    /// of garbled characters if `range` doesn't start and end on character boundaries.
+    /// # Example
+    /// ```
+    /// let mut db = mu_db::DataBase::new("./test_get_range.db").unwrap();
+    /// db.clear_all().unwrap();
+    /// db.insert("key", "héllo");
+    /// assert_eq!(db.get_range("key", 0..3).unwrap(), Some("hé".to_string()));
+    /// assert_eq!(
+    ///     db.get_range("key", 0..2).unwrap_err().kind(),
+    ///     std::io::ErrorKind::InvalidData
+    /// );
+    /// assert_eq!(db.get_range("missing", 0..2).unwrap(), None);
+    /// ```
+    pub fn get_range(&mut self, key: &str, range: Range<usize>) -> Result<Option<String>> {
+        let Some(bytes) = self.get_range_bytes(key, range.clone())? else {
+            return Ok(None);
+        };
+        if bytes
+            .first()
+            .is_some_and(|&b| b & 0b1100_0000 == 0b1000_0000)
+        {
+            return Err(boundary_error(range.start));
+        }
+        String::from_utf8(bytes)
+            .map(Some)
+            .map_err(|e| match e.utf8_error().error_len() {
+                // the last character is cut off:
+                None => boundary_error(range.end),
+                Some(_) => std::io::Error::new(std::io::ErrorKind::InvalidData, e),
+            })
+    }
+    /// Removes the entry associated with the given key from the index if the key exists.
+    /// This method does not remove the value in the database file, its bytes become a free gap
+    /// that the next insert whose value fits reuses. The bytes aren't zeroed, to give the space
+    /// back to the file system, use (`.shrink()`) after removing the entry.
+    ///
+    /// Returns the removed value, `None` if the key didn't exist. The value is read before the
+    /// entry is removed, if it can't be read the error is returned and the entry is kept.
+    /// # Example
+    /// ```
+    /// let mut db = mu_db::DataBase::new("./test.db").unwrap();
+    /// db.insert("key", "value");
+    /// assert_eq!(db.get("key"), Some("value".to_string()));
+    /// assert_eq!(db.remove("key").unwrap(), Some("value".to_string()));
+    /// assert_eq!(db.get("key"), None);
+    /// assert_eq!(db.remove("key").unwrap(), None);
+    /// ```
+    /// A value that can't be read is kept:
+    /// ```
+    /// let mut db = mu_db::DataBase::new("./test_remove_unreadable.db").unwrap();
+    /// db.clear_all().unwrap();
+    /// db.insert("k1", "hello");
+    /// db.insert("k2", "world");
+    /// db.set_buf_len(7);
+    /// assert!(db.remove("k2").is_err());
+    /// assert!(db.contains_key("k2"));
+    /// ```
+    /// Reusing the space of a removed value:
+    /// ```
+    /// let mut db = mu_db::DataBase::new("./test_remove_reuse.db").unwrap();
+    /// db.clear_all().unwrap();
+    /// db.insert("a", "0123456789");
+    /// db.insert("b", "tail");
+    /// db.remove("a").unwrap();
+    /// db.insert("c", "smaller");
+    /// assert_eq!(db.buf_len(), 14);
+    /// assert_eq!(db.get("c"), Some("smaller".to_string()));
+    /// ```
+    pub fn remove(&mut self, key: &str) -> Result<Option<String>> {
+        let Some(entry) = self.index.get_entry(key) else {
+            return Ok(None);
+        };
+        let value = self.fetch_value(&entry)?;
+        self.discard(key);
+        self.compact_step()?;
+        Ok(Some(value))
+    }
+    /// Same as (`.remove()`) without reading the value and without the compaction step.
+    fn discard(&mut self, key: &str) {
+        self.index.remove_entry(key);
+        if self.last_inserted.as_deref() == Some(key) {
+            self.last_inserted = None;
+        }
+    }
+    /// Removes all `keys` like (`.remove()`) in a loop, with a single index write at the end.
+    /// Returns how many of them existed.
+    /// # Example
+    /// ```
+    /// let mut db = mu_db::DataBase::new("./test_remove_batch.db").unwrap();
+    /// db.clear_all().unwrap();
+    /// db.insert_many(&[("session:1", "a"), ("session:2", "b"), ("user:1", "c")]);
+    /// assert_eq!(db.remove_batch(&["session:1", "session:2", "session:3"]), 2);
+    /// assert_eq!(db.keys().collect::<Vec<_>>(), ["user:1"]);
+    /// ```
+    pub fn remove_batch(&mut self, keys: &[&str]) -> usize {
+        let mut removed = 0;
+        for key in keys {
+            self.index.promote(key).unwrap();
+            if let Some(pos) = self.index.position(key) {
+                self.index.remove_at(pos);
+                removed += 1;
+                if self.last_inserted.as_deref() == Some(key) {
+                    self.last_inserted = None;
+                }
+            }
+        }
+        if removed > 0 {
+            self.index.write_index();
+        }
+        self.compact_step_or_log();
+        removed
+    }
+    /// Inserts at most the first `max_len` bytes of `value`, cut at a character boundary so
+    /// the stored value stays valid UTF-8. Returns the number of bytes stored.
+    /// # Example
+    /// ```
+    /// let mut db = mu_db::DataBase::new("./test_insert_truncated.db").unwrap();
+    /// db.clear_all().unwrap();
+    /// assert_eq!(db.insert_truncated("log", "café olé", 4), 3);
+    /// assert_eq!(db.get("log"), Some("caf".to_string()));
+    /// assert_eq!(db.insert_truncated("log", "short", 100), 5);
+    /// ```
+    pub fn insert_truncated(&mut self, key: &str, value: &str, max_len: usize) -> usize {
+        let mut len = value.len().min(max_len);
+        while !value.is_char_boundary(len) {
+            len -= 1;
+        }
+        self.insert(key, &value[..len]);
+        len
+    }
+    /// Inserts `value` if `key` doesn't exist, or replaces the existing value only if
+    /// `cmp(existing, value)` returns `true`. Returns whether `value` was stored, or the
+    /// error reading the existing value.
+    /// # Example
+    /// ```
+    /// let mut db = mu_db::DataBase::new("./test_insert_if.db").unwrap();
+    /// db.clear_all().unwrap();
+    /// let higher = |old: &str, new: &str| new.parse::<u32>().unwrap() > old.parse().unwrap();
+    /// assert!(db.insert_if("high_score", "40", higher).unwrap());
+    /// assert!(!db.insert_if("high_score", "25", higher).unwrap());
+    /// assert!(db.insert_if("high_score", "130", higher).unwrap());
+    /// assert_eq!(db.get("high_score"), Some("130".to_string()));
+    /// ```
+    pub fn insert_if(
+        &mut self,
+        key: &str,
+        value: &str,
+        cmp: impl Fn(&str, &str) -> bool,
+    ) -> Result<bool> {
+        if let Some(e) = self.index.get_entry(key) {
+            let existing = self.fetch_value(&e)?;
+            if !cmp(&existing, value) {
+                return Ok(false);
+            }
+        }
+        self.insert(key, value);
+        Ok(true)
+    }
+    /// Applies `ops` in order and writes the index once at the end.
+    ///
+    /// Values are appended after all existing data, so nothing the current index points to
+    /// is overwritten; if a write fails the in-memory index is rolled back and the index
+    /// file is left as it was (a single file index is only moved after the written values).
+    /// This isn't a transaction: the batch isn't isolated and a crash before the index write
+    /// loses it. Run (`.shrink()`) to reclaim the replaced values.
+    /// # Example
+    /// ```
+    /// use mu_db::Op;
+    /// let mut db = mu_db::DataBase::new("./test_apply.db").unwrap();
+    /// db.clear_all().unwrap();
+    /// db.insert("a", "1");
+    /// db.insert("b", "2");
+    /// db.apply(&[
+    ///     Op::insert("a", "10"),
+    ///     Op::remove("b"),
+    ///     Op::insert("c", "3"),
+    /// ])
+    /// .unwrap();
+    /// assert_eq!(db.get("a"), Some("10".to_string()));
+    /// assert_eq!(db.get("b"), None);
+    /// assert_eq!(db.get("c"), Some("3".to_string()));
+    /// assert_eq!(db.head("a").unwrap().start, 2);
+    /// ```
+    /// A single file database writes its index once too:
+    /// ```
+    /// use mu_db::Op;
+    /// let mut db = mu_db::DataBase::builder("./test_apply_single_file.db")
+    ///     .single_file(true)
+    ///     .open()
+    ///     .unwrap();
+    /// db.clear_all().unwrap();
+    /// let writes = db.index_writes();
+    /// db.apply(&[Op::insert("a", "1"), Op::insert("b", "2"), Op::insert("c", "3")])
+    ///     .unwrap();
+    /// assert_eq!(db.index_writes(), writes + 1);
+    /// drop(db);
+    /// let mut db = mu_db::DataBase::builder("./test_apply_single_file.db")
+    ///     .single_file(true)
+    ///     .open()
+    ///     .unwrap();
+    /// assert_eq!(db.get("c"), Some("3".to_string()));
+    /// ```
+    pub fn apply(&mut self, ops: &[Op]) -> Result<()> {
+        let ops: Vec<(&str, Option<&[u8]>)> = ops
+            .iter()
+            .map(|op| match op {
+                Op::Insert { key, value } => (key.as_str(), Some(value.as_bytes())),
+                Op::Remove { key } => (key.as_str(), None),
+            })
+            .collect();
+        self.apply_raw(&ops)
+    }
+    /// Same as (`.apply()`) for binary values: `(key, Some(value))` inserts and `(key, None)`
+    /// removes.
+    fn apply_raw(&mut self, ops: &[(&str, Option<&[u8]>)]) -> Result<()> {
+        self.run_batch(|db, written_end| db.apply_ops(ops, written_end))
+    }
+    /// Runs `batch`, which places and writes values and sets its second argument to the end of
+    /// the values written, then writes the index once. If `batch` fails, the in-memory index
+    /// is rolled back.
+    fn run_batch(&mut self, batch: impl FnOnce(&mut Self, &mut u64) -> Result<()>) -> Result<()> {
+        let checkpoint = self.index.checkpoint();
+        let last_inserted = self.last_inserted.clone();
+        let mut written_end = 0;
+        let result = batch(self, &mut written_end);
+        if result.is_err() {
+            self.index.restore(checkpoint);
+            self.last_inserted = last_inserted;
+        }
+        match self.index.embedded_len() {
+            // values of the batch overwrote the embedded index block, it's moved after them
+            // once the whole batch is placed:
+            Some(len) if written_end > len => {
+                let moved = self.index.set_embedded_len(written_end);
+                result.and(moved)
+            }
+            _ => {
+                if result.is_ok() {
+                    self.index.write_index();
+                }
+                result
+            }
+        }
+    }
+    /// Places and writes the values of `ops`, `written_end` is set to the end of the last
+    /// value written.
+    fn apply_ops(&mut self, ops: &[(&str, Option<&[u8]>)], written_end: &mut u64) -> Result<()> {
+        // nothing is placed before `end`, even space freed by this batch:
+        let mut end = self.index.data_end();
+        for &(key, value) in ops {
+            match value {
+                Some(value) => {
+                    if !DataBase::is_valid_key(key) {
+                        return Err(invalid_key(key));
+                    }
+                    let value = self.encode(value).into_owned();
+                    let start = end.max(self.index.data_end());
+                    self.index.append_entry(value.len(), key, start);
+                    end = start + value.len();
+                    *written_end = end as u64;
+                    self.write_values(start as u64, &value)?;
+                    self.last_inserted = Some(key.to_string());
+                }
+                None => {
+                    self.index.promote(key)?;
+                    if let Some(pos) = self.index.position(key) {
+                        self.index.remove_at(pos);
+                    }
+                    if self.last_inserted.as_deref() == Some(key) {
+                        self.last_inserted = None;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+    /// Removes every entry whose value lies entirely within `range` of the db file, e.g. a
+    /// region known to be damaged, with a single index write. Returns the removed keys in
+    /// offset order.
+    /// # Example
+    /// ```
+    /// let mut db = mu_db::DataBase::new("./test_remove_in_range.db").unwrap();
+    /// db.clear_all().unwrap();
+    /// for k in ["k1", "k2", "k3", "k4"] {
+    ///     db.insert(k, "value");
+    /// }
+    /// assert_eq!(db.remove_entries_in_range(3..12), vec!["k2"]);
+    /// assert_eq!(db.get("k2"), None);
+    /// assert_eq!(db.get("k3"), Some("value".to_string()));
+    /// ```
+    pub fn remove_entries_in_range(&mut self, range: Range<usize>) -> Vec<String> {
+        let removed = self
+            .index
+            .remove_entries_where(|e| range.start <= e.range.start && e.range.end <= range.end);
+        if removed
+            .iter()
+            .any(|k| self.last_inserted.as_ref() == Some(k))
+        {
+            self.last_inserted = None;
+        }
+        removed
+    }
+    /// Adds `by` to the integer value of `key` (`0` if the key doesn't exist), stores the
+    /// result and returns it.
+    ///
+    /// Returns an `InvalidData` error if the existing value isn't an integer or the result
+    /// overflows, the value is left unchanged in that case.
+    /// # Example
+    /// ```
+    /// let mut db = mu_db::DataBase::new("./test_increment.db").unwrap();
+    /// db.clear_all().unwrap();
+    /// assert_eq!(db.increment("counter", 5).unwrap(), 5);
+    /// assert_eq!(db.increment("counter", -7).unwrap(), -2);
+    /// assert_eq!(db.get("counter"), Some("-2".to_string()));
+    /// db.insert("name", "mu");
+    /// assert!(db.increment("name", 1).is_err());
+    /// ```
+    pub fn increment(&mut self, key: &str, by: i64) -> Result<i64> {
+        let invalid = |msg: String| std::io::Error::new(std::io::ErrorKind::InvalidData, msg);
+        let current = match self.get(key) {
+            Some(v) => v
+                .parse::<i64>()
+                .map_err(|_| invalid(format!("value of `{}` is not an integer", key)))?,
+            None => 0,
+        };
+        let new = current
+            .checked_add(by)
+            .ok_or_else(|| invalid(format!("incrementing `{}` overflows", key)))?;
+        self.insert(key, &new.to_string());
+        Ok(new)
+    }
+    /// Returns the key of the most recent (`.insert()`) call, or `None` if that key has been
+    /// removed since. This isn't persisted, a reopened database starts with `None`.
+    ///
+    /// Note that it isn't necessarily the entry with the highest offset, since new values can
+    /// be placed in gaps.
+    /// # Example
+    /// ```
+    /// let mut db = mu_db::DataBase::new("./test_last_inserted.db").unwrap();
+    /// db.clear_all().unwrap();
+    /// assert_eq!(db.last_inserted(), None);
+    /// db.insert("k1", "a");
+    /// db.insert("k2", "b");
+    /// assert_eq!(db.last_inserted(), Some("k2"));
+    /// db.remove("k1").unwrap();
+    /// assert_eq!(db.last_inserted(), Some("k2"));
+    /// db.remove("k2").unwrap();
+    /// assert_eq!(db.last_inserted(), None);
+    /// ```
+    pub fn last_inserted(&self) -> Option<&str> {
+        self.last_inserted.as_deref()
+    }
+    /// Case-insensitive version of (`.insert()`).
+    ///
+    /// The key is stored lowercased (`str::to_lowercase`), so keys that differ only in case
+    /// refer to the same entry and the last insert wins. Use (`.get_ci()`) and (`.remove_ci()`)
+    /// to access it, plain (`.get()`) only matches the lowercased key.
+    /// # Example
+    /// ```
+    /// let mut db = mu_db::DataBase::new("./test_ci.db").unwrap();
+    /// db.clear_all().unwrap();
+    /// db.insert_ci("Key", "first");
+    /// db.insert_ci("KEY", "second");
+    /// assert_eq!(db.get_ci("key"), Some("second".to_string()));
+    /// assert_eq!(db.get("key"), Some("second".to_string()));
+    /// db.remove_ci("kEy").unwrap();
+    /// assert_eq!(db.get_ci("Key"), None);
+    /// ```
+    pub fn insert_ci(&mut self, key: &str, value: &str) {
+        self.insert(&key.to_lowercase(), value);
+    }
+    /// Case-insensitive version of (`.get()`), see (`.insert_ci()`).
+    pub fn get_ci(&mut self, key: &str) -> Option<String> {
+        self.get(&key.to_lowercase())
+    }
+    /// Case-insensitive version of (`.remove()`), see (`.insert_ci()`).
+    pub fn remove_ci(&mut self, key: &str) -> Result<Option<String>> {
+        self.remove(&key.to_lowercase())
+    }
+    /// Serializes `value` and inserts it under `key`, requires the `json` feature.
+    /// # Example
+    /// ```
+    /// # #[cfg(feature = "json")]
+    /// # {
+    /// let mut db = mu_db::DataBase::new("./test_json.db").unwrap();
+    /// let value = serde_json::json!({ "name": "mu", "tags": [1, 2] });
+    /// db.insert_json("key", &value);
+    /// assert_eq!(db.get_json("key").unwrap(), Some(value));
+    /// assert_eq!(db.get_json("missing").unwrap(), None);
+    /// # }
+    /// ```
+    #[cfg(feature = "json")]
+    pub fn insert_json(&mut self, key: &str, value: &serde_json::Value) {
+        self.insert(key, &value.to_string());
+    }
+    /// Retrieves and parses the JSON value of `key`, requires the `json` feature.
+    ///
+    /// Returns an `InvalidData` error if the stored value isn't valid JSON.
+    #[cfg(feature = "json")]
+    pub fn get_json(&mut self, key: &str) -> Result<Option<serde_json::Value>> {
+        match self.get(key) {
+            Some(v) => serde_json::from_str(&v)
+                .map(Some)
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e)),
+            None => Ok(None),
+        }
+    }
+    /// Clears all data in the database.
+    ///
+    /// Bytes still buffered by the writer are discarded and both file handles start over at
+    /// offset 0, so the database behaves like a freshly created one.
+    /// # Example
+    /// ```
+    /// let mut db = mu_db::DataBase::new("./test.db").unwrap();
+    /// db.insert("key", "value");
+    /// assert!(!db.is_empty());
+    /// assert!(!db.is_buf_empty());
+    /// db.clear_all().unwrap();
+    /// assert!(db.is_empty());
+    /// assert!(db.is_buf_empty());
+    ///
+    /// let mut db = mu_db::DataBase::builder("./test_clear_all_buffered.db")
+    ///     .flush_every_bytes(1 << 16)
+    ///     .open()
+    ///     .unwrap();
+    /// db.insert("old", "stale bytes");
+    /// db.clear_all().unwrap();
+    /// db.insert("new", "fresh");
+    /// assert_eq!(db.head("new").unwrap().start, 0);
+    /// assert_eq!(db.buf_len(), 5);
+    /// assert_eq!(db.read_at(0, 5).unwrap(), "fresh");
+    /// ```
+    pub fn clear_all(&mut self) -> Result<()> {
+        self.index.clear_all();
+        wait_data_writer(&self.background)?;
+        {
+            let mut bw = self.writer.lock().unwrap();
+            let file = bw.get_ref().try_clone()?;
+            let stale = std::mem::replace(&mut *bw, data_writer(file, &self.options));
+            // drop the buffered bytes without flushing them:
+            let _ = stale.into_parts();
+        }
+        self.set_buf_len(0);
+        self.last_inserted = None;
+
+        Ok(())
+    }
+    /// Same as (`.clear_all()`) but keeps the reserved prefix, see
+    /// (`DataBaseBuilder::reserve_prefix()`).
+    fn clear_values(&mut self) -> Result<()> {
+        let prefix = self.options.reserve_prefix as u64;
+        if prefix == 0 {
+            return self.clear_all();
+        }
+        self.index.clear_all();
+        self.set_buf_len(prefix.min(self.buf_len()));
+        self.last_inserted = None;
+        Ok(())
+    }
+    /// Optimizes the database file by removing any unused space.
+    /// # Example
+    /// ```
+    /// let mut db = mu_db::DataBase::new("./test.db").unwrap();
+    /// db.clear_all();
+    /// db.insert("k1", "1".repeat(10).as_str());
+    /// db.insert("k2", "2".repeat(10).as_str());
+    /// assert_eq!(db.buf_len(), 20);
+    /// db.remove("k1").unwrap();
+    /// assert_eq!(db.buf_len(), 20);
+    /// db.insert("k3", "3".repeat(5).as_str());
+    /// assert_eq!(db.buf_len(), 20);
+    /// db.shrink();
+    /// assert_eq!(db.buf_len(), 15);
+    /// db.remove("k2").unwrap();
+    /// db.remove("k3").unwrap();
+    /// assert_eq!(db.buf_len(), 15);
+    /// db.shrink();
+    /// assert_eq!(db.buf_len(), 0);
+    /// ```
+    /// Values are moved as raw bytes, so binary values survive:
+    /// ```
+    /// let mut db = mu_db::DataBase::new("./test_shrink_binary.db").unwrap();
+    /// db.clear_all().unwrap();
+    /// db.insert("a", "0123456789");
+    /// db.insert_bytes("b", &[0xff, 0xfe, 0x00]);
+    /// db.remove("a").unwrap();
+    /// db.shrink();
+    /// assert_eq!(db.buf_len(), 3);
+    /// assert_eq!(db.get_bytes("b"), Some(vec![0xff, 0xfe, 0x00]));
+    /// ```
+    pub fn shrink(&mut self) {
+        if self.index.is_empty() {
+            self.clear_values().unwrap();
+            return;
+        }
+
+        let old_entries = self.index.shrink_entries();
+
+        let mut buf = Vec::new();
+        for (old, new) in old_entries.iter().zip(self.index.entries.clone()) {
+            if old.range.start != new.range.start {
+                buf.resize(old.size(), 0);
+                self.read_into(old.range.start as u64, &mut buf).unwrap();
+                self.write_bytes(new.range.start as u64, &buf).unwrap();
+            }
+        }
+
+        self.set_buf_len(
+            (self.index.entries.last().unwrap().range.end)
+                .try_into()
+                .unwrap(),
+        );
+        if self.options.drop_cache_after_shrink {
+            self.drop_page_cache().unwrap();
+        }
+    }
+
+    /// Same as (`.shrink()`) but moves entries with large sequential reads and writes.
+    ///
+    /// Live entries are read in offset order into a buffer of up to `SHRINK_BUFFER_SIZE` bytes
+    /// (a single entry larger than that gets its own buffer), then written back to back at
+    /// their compacted position, so a fragmented file is compacted with few seeks.
+    /// # Example
+    /// ```
+    /// let mut db = mu_db::DataBase::new("./test_shrink_sequential.db").unwrap();
+    /// db.clear_all().unwrap();
+    /// for i in 0..20 {
+    ///     db.insert(&format!("k{}", i), &i.to_string().repeat(i + 1));
+    /// }
+    /// for i in (0..20).step_by(3) {
+    ///     db.remove(&format!("k{}", i)).unwrap();
+    /// }
+    /// db.shrink_sequential().unwrap();
+    /// for i in 0..20 {
+    ///     let expected = if i % 3 == 0 { None } else { Some(i.to_string().repeat(i + 1)) };
+    ///     assert_eq!(db.get(&format!("k{}", i)), expected);
+    /// }
+    /// let live: usize = (0..20).filter(|i| i % 3 != 0).map(|i| i.to_string().len() * (i + 1)).sum();
+    /// assert_eq!(db.buf_len(), live as u64);
+    /// ```
+    pub fn shrink_sequential(&mut self) -> Result<()> {
+        self.index.load_all()?;
+        if self.index.is_empty() {
+            return self.clear_values();
+        }
+
+        let old_entries = self.index.shrink_entries();
+        let new_entries = self.index.get_all_entries();
+
+        let mut buf = Vec::with_capacity(SHRINK_BUFFER_SIZE);
+        let mut i = 0;
+        while i < old_entries.len() {
+            if old_entries[i].range.start == new_entries[i].range.start {
+                i += 1;
+                continue;
+            }
+            // batch entries whose source span fits in the buffer:
+            let src_start = old_entries[i].range.start;
+            let mut j = i + 1;
+            while j < old_entries.len()
+                && old_entries[j].range.end - src_start <= SHRINK_BUFFER_SIZE
+            {
+                j += 1;
+            }
+            let src_end = old_entries[j - 1].range.end;
+            buf.resize(src_end - src_start, 0);
+            self.read_into(src_start as u64, &mut buf)?;
+
+            // pack the batch, entries only move towards the start of the file:
+            let mut packed = 0;
+            for old in &old_entries[i..j] {
+                let from = old.range.start - src_start;
+                buf.copy_within(from..from + old.size(), packed);
+                packed += old.size();
+            }
+            self.write_bytes(new_entries[i].range.start as u64, &buf[..packed])?;
+            i = j;
+        }
+
+        self.set_buf_len(new_entries.last().unwrap().range.end as u64);
+        if self.options.drop_cache_after_shrink {
+            self.drop_page_cache()?;
+        }
+        Ok(())
+    }
+    /// Does a bounded part of (`.shrink()`): moves entries towards the start of the file, in
+    /// offset order starting at the first gap, until moving the next one would exceed
+    /// `max_bytes`. Returns the number of bytes moved.
+    ///
+    /// Entries larger than `max_bytes` are skipped and left for (`.shrink()`). Once no gap is
+    /// left, the file is truncated to the end of the last entry. Unlike (`.shrink()`), bytes
+    /// reserved by [ShrinkTailPolicy::Keep] are kept.
+    /// # Example
+    /// ```
+    /// let mut db = mu_db::DataBase::new("./test_shrink_step.db").unwrap();
+    /// db.clear_all().unwrap();
+    /// db.insert("k1", "aaaa");
+    /// db.insert("k2", "bbbb");
+    /// db.insert("k3", "cccc");
+    /// db.remove("k1").unwrap();
+    /// assert_eq!(db.shrink_step(6).unwrap(), 4);
+    /// assert_eq!(db.read_at(0, 4).unwrap(), "bbbb");
+    /// assert_eq!(db.shrink_step(6).unwrap(), 4);
+    /// assert_eq!(db.shrink_step(6).unwrap(), 0);
+    /// assert_eq!(db.buf_len(), 8);
+    /// assert_eq!(db.get("k3"), Some("cccc".to_string()));
+    /// ```
+    pub fn shrink_step(&mut self, max_bytes: usize) -> Result<usize> {
+        self.index.load_all()?;
+        let mut moved = 0;
+        let mut prev_end = self.index.reserved_prefix;
+        let mut buf = Vec::new();
+        for i in 0..self.index.entries.len() {
+            let e = &self.index.entries[i];
+            let (start, size) = (e.range.start, e.size());
+            if start <= prev_end || size > max_bytes {
+                prev_end = prev_end.max(e.occupied_end());
+                continue;
+            }
+            if moved + size > max_bytes {
+                self.index.write_index();
+                return Ok(moved);
+            }
+            buf.resize(size, 0);
+            self.read_into(start as u64, &mut buf)?;
+            self.write_bytes(prev_end as u64, &buf)?;
+            let e = &mut self.index.entries[i];
+            e.range = prev_end..prev_end + size;
+            prev_end = e.occupied_end();
+            moved += size;
+            self.index.free = None;
+        }
+        if moved != 0 {
+            self.index.write_index();
+        }
+        if self.buf_len() > prev_end as u64 {
+            self.set_buf_len(prev_end as u64);
+        }
+        Ok(moved)
+    }
+    /// Runs (`.shrink_step()`) with the budget of
+    /// (`DataBaseBuilder::amortized_compaction()`), then (`.shrink()`) if the file is more
+    /// fragmented than (`DataBaseBuilder::auto_shrink()`) allows. The data the mutation wrote
+    /// is already in place, so an error here only means the file wasn't compacted.
+    fn compact_step(&mut self) -> Result<()> {
+        if let Some(max_bytes) = self.options.compaction_budget {
+            self.shrink_step(max_bytes)?;
+        }
+        if let Some(threshold) = self.options.auto_shrink {
+            if self.fragmentation() > threshold {
+                self.shrink_sequential()?;
+            }
+        }
+        Ok(())
+    }
+    /// Runs (`.compact_step()`) after a mutation that can't return an error, reporting a
+    /// failure on stderr instead: the next mutation tries again.
+    fn compact_step_or_log(&mut self) {
+        if let Err(e) = self.compact_step() {
+            eprintln!("mu_db: compacting {} failed: {}", self.path.display(), e);
+        }
+    }
+    /// Reads the whole db file sequentially, in chunks of `SHRINK_BUFFER_SIZE` bytes, so it's
+    /// in the OS page cache before random (`.get()`) calls need it.
+    /// # Example
+    /// ```
+    /// let mut db = mu_db::DataBase::new("./test_prewarm.db").unwrap();
+    /// db.insert("key", "value");
+    /// db.prewarm().unwrap();
+    /// assert_eq!(db.get("key"), Some("value".to_string()));
+    /// ```
+    pub fn prewarm(&mut self) -> Result<()> {
+        self.flush_pending()?;
+        let mut buf = vec![0; SHRINK_BUFFER_SIZE];
+        let mut br = self.reader.lock().unwrap();
+        br.seek(SeekFrom::Start(0))?;
+        loop {
+            match br.read(&mut buf) {
+                Ok(0) => return Ok(()),
+                Ok(_) => {}
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+    }
+    /// Advises the OS to drop the db file from the page cache, useful after a large
+    /// sequential scan. Does nothing on non-Linux targets.
+    /// # Example
+    /// ```
+    /// let mut db = mu_db::DataBase::new("./test_drop_page_cache.db").unwrap();
+    /// db.insert("key", "value");
+    /// db.drop_page_cache().unwrap();
+    /// assert_eq!(db.get("key"), Some("value".to_string()));
+    /// ```
+    pub fn drop_page_cache(&self) -> Result<()> {
+        #[cfg(target_os = "linux")]
+        {
+            use std::os::unix::io::AsRawFd;
+
+            wait_data_writer(&self.background)?;
+            self.writer.lock().unwrap().flush()?;
+            let fd = self.reader.lock().unwrap().get_ref().as_raw_fd();
+            // SAFETY: `fd` is a valid open descriptor owned by `self.reader` for the whole call.
+            let ret = unsafe { libc::posix_fadvise(fd, 0, 0, libc::POSIX_FADV_DONTNEED) };
+            if ret != 0 {
+                return Err(std::io::Error::from_raw_os_error(ret));
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads data directly from the database file at the specified position (`start`) and size (`size`).
+    ///
+    /// The read is raw: bytes outside the current entries, e.g. the tail of a value that was
+    /// overwritten with a shorter one or a removed value not yet reclaimed by (`.shrink()`),
+    /// are returned as they are. Use (`.read_value()`) to read exactly the current value of a key.
+    /// # Example
+    /// ```
+    /// let mut db = mu_db::DataBase::new("./test.db").unwrap();
+    /// db.clear_all();
+    /// db.insert("k1", "hello");
+    /// db.insert("k2", "world");
+    /// assert_eq!(db.read_at(5, 5).unwrap(), "world".to_string());
+    /// ```
+    ///
+    /// # Read-your-writes
+    /// The reader and writer are separate handles, so any bytes still pending in the
+    /// writer's buffer are flushed before reading. A `read_at` (or `get`) right after
+    /// a `write_at` (or `insert`) always sees the written bytes.
+    /// ```
+    /// let mut db = mu_db::DataBase::new("./test_read_your_writes.db").unwrap();
+    /// db.clear_all().unwrap();
+    /// db.insert("key", "before");
+    /// assert_eq!(db.get("key"), Some("before".to_string()));
+    /// db.insert("key", "after!");
+    /// assert_eq!(db.get("key"), Some("after!".to_string()));
+    /// db.write_at(0, "AF").unwrap();
+    /// assert_eq!(db.read_at(0, 6).unwrap(), "AFter!".to_string());
+    /// ```
+    pub fn read_at(&mut self, start: u64, size: usize) -> Result<String> {
+        let v = self.read_bytes_at(start, size)?;
+        Ok(String::from_utf8_lossy(&v).into())
+    }
+    /// Same as (`.read_at()`) but returns the exact bytes, without replacing invalid UTF-8.
+    /// # Example
+    /// ```
+    /// let mut db = mu_db::DataBase::new("./test_read_bytes_at.db").unwrap();
+    /// db.clear_all().unwrap();
+    /// db.insert_bytes("blob", &[0xff, 0x00, 0xfe]);
+    /// assert_eq!(db.read_bytes_at(0, 3).unwrap(), vec![0xff, 0x00, 0xfe]);
+    /// assert_eq!(db.read_at(0, 1).unwrap(), "\u{fffd}");
+    /// ```
+    pub fn read_bytes_at(&mut self, start: u64, size: usize) -> Result<Vec<u8>> {
+        let mut v = vec![0; size];
+        self.read_into(start, &mut v)?;
+        Ok(v)
+    }
+    /// Reads the stored bytes of `key`, exactly the range of its current entry, or `None` if the
+    /// key doesn't exist. Unlike (`.get()`) no [ValueCodec] is applied.
+    /// # Example
+    /// ```
+    /// let mut db = mu_db::DataBase::new("./test_read_value.db").unwrap();
+    /// db.clear_all().unwrap();
+    /// db.insert("key", "longer value");
+    /// db.insert("key", "short");
+    /// let start = db.head("key").unwrap().start as u64;
+    /// // the old value is still in the db file after the current one:
+    /// assert_eq!(db.read_at(start, 12).unwrap(), "shortr value");
+    /// assert_eq!(db.read_value("key").unwrap(), Some("short".to_string()));
+    /// assert_eq!(db.read_value("missing").unwrap(), None);
+    /// ```
+    pub fn read_value(&mut self, key: &str) -> Result<Option<String>> {
+        let Some(e) = self.index.get_entry(key) else {
+            return Ok(None);
+        };
+        if let Some(value) = e.inline {
+            return Ok(Some(value));
+        }
+        self.read_at(e.range.start as u64, e.size()).map(Some)
+    }
+    /// Same as (`.read_at()`) but reads up to `size` bytes, a read that reaches the end of
+    /// the db file returns the available data instead of an error.
+    /// # Example
+    /// ```
+    /// let mut db = mu_db::DataBase::new("./test_read_at_lossy.db").unwrap();
+    /// db.clear_all().unwrap();
+    /// db.write_at(0, "hello").unwrap();
+    /// assert_eq!(db.read_at_lossy(3, 10).unwrap(), "lo".to_string());
+    /// assert_eq!(db.read_at_lossy(10, 10).unwrap(), "".to_string());
+    /// assert!(db.read_at(3, 10).is_err());
+    /// ```
+    pub fn read_at_lossy(&mut self, start: u64, size: usize) -> Result<String> {
+        self.flush_pending()?;
+        let mut v = Vec::with_capacity(size);
+        let mut br = self.reader.lock().unwrap();
+        br.seek(SeekFrom::Start(start))?;
+        br.by_ref().take(size as u64).read_to_end(&mut v)?;
+        Ok(String::from_utf8_lossy(&v).into())
+    }
+    /// Flushes bytes still queued or buffered in the writer so the reader sees them.
+    fn flush_pending(&self) -> Result<()> {
+        wait_data_writer(&self.background)?;
+        let mut bw = self.writer.lock().unwrap();
+        if !bw.buffer().is_empty() {
+            bw.flush()?;
+        }
+        Ok(())
+    }
+    /// Fills `buf` with the bytes at `start`, flushing pending writes first.
+    fn read_into(&self, start: u64, buf: &mut [u8]) -> Result<()> {
+        self.flush_pending()?;
+        let mut br = self.reader.lock().unwrap();
+        retry_transient(self.options.io_retries, || {
+            br.seek(SeekFrom::Start(start))?;
+            br.read_exact(buf)
+        })
+    }
+    /// Writes data directly to the database file at the specified position with any length.
+    /// # Example
+    /// ```
+    /// let mut db = mu_db::DataBase::new("./test.db").unwrap();
+    /// db.clear_all();
+    /// db.write_at(5, "world").unwrap();
+    /// assert_eq!(db.read_at(5, 5).unwrap(), "world".to_string());
+    /// ```
+    pub fn write_at(&mut self, start: u64, content: &str) -> Result<()> {
+        self.write_bytes(start, content.as_bytes())
+    }
+    fn write_bytes(&mut self, start: u64, content: &[u8]) -> Result<()> {
+        self.write_values(start, content)?;
+        // a write past the values overwrote the embedded index block, move it after the write:
+        let end = start + content.len() as u64;
+        match self.index.embedded_len() {
+            Some(len) if end > len => self.index.set_embedded_len(end),
+            _ => Ok(()),
+        }
+    }
+    /// Same as `write_bytes` without moving the embedded index block, the caller does that.
+    fn write_values(&mut self, start: u64, content: &[u8]) -> Result<()> {
+        let flush_at = if self.auto_flush {
+            self.options.flush_every_bytes.unwrap_or(0)
+        } else {
+            usize::MAX
+        };
+        match &self.background {
+            Some(jobs) => jobs
+                .send(DataJob::Write(start, content.to_vec(), flush_at))
+                .map_err(|_| data_writer_stopped())?,
+            None => write_data(
+                &mut self.writer.lock().unwrap(),
+                start,
+                content,
+                flush_at,
+                self.options.io_retries,
+            )?,
+        }
+        self.data_len = self.data_len.max(start + content.len() as u64);
+        Ok(())
+    }
+    /// Hands everything buffered to the OS: values kept back by
+    /// (`DataBaseBuilder::flush_every_bytes()`) and index writes deferred by
+    /// (`DataBaseBuilder::coalesce_index_writes()`). Unlike (`.flush_all()`) it doesn't wait
+    /// until the files are on disk, so it survives a process crash but not a power loss.
+    /// # Example
+    /// ```
+    /// use std::time::Duration;
+    /// let mut db = mu_db::DataBase::builder("./test_flush.db")
+    ///     .flush_every_bytes(1 << 16)
+    ///     .coalesce_index_writes(Duration::from_secs(60), 1000)
+    ///     .open()
+    ///     .unwrap();
+    /// db.clear_all().unwrap();
+    /// for i in 0..1000 {
+    ///     db.insert(&format!("k{}", i), "value");
+    /// }
+    /// db.flush().unwrap();
+    /// assert_eq!(std::fs::metadata("./test_flush.db").unwrap().len(), 5000);
+    /// let index = std::fs::read_to_string("./index_test_flush.db").unwrap();
+    /// assert_eq!(index.lines().count(), 1000);
+    /// ```
+    /// Dropping the database flushes too, ignoring errors:
+    /// ```
+    /// use std::time::Duration;
+    /// let mut db = mu_db::DataBase::builder("./test_flush_on_drop.db")
+    ///     .flush_every_bytes(1 << 16)
+    ///     .coalesce_index_writes(Duration::from_secs(60), 1000)
+    ///     .open()
+    ///     .unwrap();
+    /// db.clear_all().unwrap();
+    /// db.insert("key", "value");
+    /// drop(db);
+    /// assert_eq!(std::fs::read_to_string("./test_flush_on_drop.db").unwrap(), "value");
+    /// let mut db = mu_db::DataBase::new("./test_flush_on_drop.db").unwrap();
+    /// assert_eq!(db.get("key"), Some("value".to_string()));
+    /// ```
+    pub fn flush(&mut self) -> Result<()> {
+        wait_data_writer(&self.background)?;
+        self.writer.lock().unwrap().flush()?;
+        self.index.flush()?;
+        self.index.writer.lock().unwrap().flush()
+    }
+    /// Turns the flush after every write to the db file on (the default) or off. Off is
+    /// (`DataBaseBuilder::flush_every_bytes()`) with the size of the current buffer, so writes
+    /// reach the OS when the buffer fills, before reads and on (`.flush()`). Turning it back
+    /// on flushes the buffer and restores the configured (`DataBaseBuilder::flush_every_bytes()`).
+    /// # Example
+    /// ```
+    /// let mut db = mu_db::DataBase::new("./test_set_auto_flush.db").unwrap();
+    /// db.clear_all().unwrap();
+    /// db.set_auto_flush(false).unwrap();
+    /// for i in 0..100 {
+    ///     db.insert(&format!("k{}", i), "value");
+    /// }
+    /// assert_eq!(std::fs::metadata("./test_set_auto_flush.db").unwrap().len(), 0);
+    /// db.flush().unwrap();
+    /// assert_eq!(std::fs::metadata("./test_set_auto_flush.db").unwrap().len(), 500);
+    /// db.set_auto_flush(true).unwrap();
+    /// ```
+    /// A threshold set with (`DataBaseBuilder::flush_every_bytes()`) survives turning it off
+    /// and on again:
+    /// ```
+    /// let mut db = mu_db::DataBase::builder("./test_set_auto_flush_threshold.db")
+    ///     .flush_every_bytes(1 << 16)
+    ///     .open()
+    ///     .unwrap();
+    /// db.clear_all().unwrap();
+    /// db.set_auto_flush(false).unwrap();
+    /// db.set_auto_flush(true).unwrap();
+    /// db.insert("key", "value");
+    /// assert_eq!(std::fs::metadata("./test_set_auto_flush_threshold.db").unwrap().len(), 0);
+    /// db.flush().unwrap();
+    /// assert_eq!(std::fs::metadata("./test_set_auto_flush_threshold.db").unwrap().len(), 5);
+    /// ```
+    pub fn set_auto_flush(&mut self, enabled: bool) -> Result<()> {
+        if enabled {
+            wait_data_writer(&self.background)?;
+            self.writer.lock().unwrap().flush()?;
+        }
+        self.auto_flush = enabled;
+        Ok(())
+    }
+    /// Flushes both files and waits until they are on disk, including (on Unix) the directory
+    /// entries of the files, so a freshly created database survives a crash.
+    /// # Example
+    /// ```
+    /// let mut db = mu_db::DataBase::new("./test_flush_all.db").unwrap();
+    /// db.insert("key", "value");
+    /// db.flush_all().unwrap();
+    /// ```
+    pub fn flush_all(&mut self) -> Result<()> {
+        wait_data_writer(&self.background)?;
+        {
+            let mut bw = self.writer.lock().unwrap();
+            bw.flush()?;
+            bw.get_ref().sync_all()?;
+        }
+        self.index.sync()?;
+        #[cfg(unix)]
+        {
+            let mut dirs = vec![parent_dir(&self.path)];
+            let index_dir = parent_dir(self.index.path());
+            if index_dir != dirs[0] {
+                dirs.push(index_dir);
+            }
+            for dir in dirs {
+                File::open(dir)?.sync_all()?;
+            }
+        }
+        Ok(())
+    }
+    /// Brings the database to rest on disk, e.g. before a backup or process exit: index writes
+    /// deferred by (`DataBaseBuilder::coalesce_index_writes()`) and data buffered by
+    /// (`DataBaseBuilder::flush_every_bytes()`) are written, then (`.flush_all()`) waits until
+    /// both files are on disk.
+    ///
+    /// The only background work is the index writer thread of
+    /// (`DataBaseBuilder::background_index_writes()`) and the data writer thread of
+    /// (`DataBaseBuilder::background_data_writes()`), and this waits until both wrote
+    /// everything queued, so nothing is in flight once this returns and until the next call on
+    /// the database.
+    /// # Example
+    /// ```
+    /// use std::time::Duration;
+    /// let mut db = mu_db::DataBase::builder("./test_drain.db")
+    ///     .coalesce_index_writes(Duration::from_secs(60), 1000)
+    ///     .open()
+    ///     .unwrap();
+    /// db.clear_all().unwrap();
+    /// db.insert("key", "value");
+    /// db.drain().unwrap();
+    /// let index = std::fs::read_to_string("./index_test_drain.db").unwrap();
+    /// assert_eq!(index, "key=0_5=version:1\n");
+    ///
+    /// let mut db = mu_db::DataBase::builder("./test_drain_background.db")
+    ///     .background_index_writes(true)
+    ///     .open()
+    ///     .unwrap();
+    /// db.clear_all().unwrap();
+    /// db.insert("key", "value");
+    /// db.drain().unwrap();
+    /// let index = std::fs::read_to_string("./index_test_drain_background.db").unwrap();
+    /// assert_eq!(index, "key=0_5=version:1\n");
+    ///
+    /// let mut db = mu_db::DataBase::builder("./test_drain_background_data.db")
+    ///     .background_data_writes(true)
+    ///     .open()
+    ///     .unwrap();
+    /// db.clear_all().unwrap();
+    /// db.insert("key", "value");
+    /// db.drain().unwrap();
+    /// let data = std::fs::read_to_string("./test_drain_background_data.db").unwrap();
+    /// assert_eq!(data, "value");
+    /// ```
+    pub fn drain(&mut self) -> Result<()> {
+        self.flush_all()
+    }
+    /// Moves the db file to `new_data_path` and the index file to `new_index_path`, then keeps
+    /// using them from there. In single-file mode `new_index_path` is ignored.
+    ///
+    /// Pending writes are flushed first. Each file is renamed atomically; if the index can't
+    /// be renamed, the db file is moved back. To reopen with (`DataBase::new()`) later, the
+    /// index must be named `index_<db file name>` next to the db file.
+    /// # Example
+    /// ```
+    /// let mut db = mu_db::DataBase::new("./test_rename_db.db").unwrap();
+    /// db.clear_all().unwrap();
+    /// db.insert("key", "value");
+    /// db.rename_db("./test_renamed.db", "./index_test_renamed.db").unwrap();
+    /// assert_eq!(db.path(), std::path::Path::new("./test_renamed.db"));
+    /// assert!(!std::path::Path::new("./test_rename_db.db").exists());
+    /// db.insert("other", "value");
+    /// drop(db);
+    ///
+    /// let mut db = mu_db::DataBase::new("./test_renamed.db").unwrap();
+    /// assert_eq!(db.get("key"), Some("value".to_string()));
+    /// assert_eq!(db.get("other"), Some("value".to_string()));
+    /// ```
+    pub fn rename_db(&mut self, new_data_path: &str, new_index_path: &str) -> Result<()> {
+        self.flush_all()?;
+        let single_file = self.index.embedded_len().is_some();
+        std::fs::rename(&self.path, new_data_path)?;
+        if !single_file {
+            if let Err(e) = std::fs::rename(&self.index.path, new_index_path) {
+                std::fs::rename(new_data_path, &self.path)?;
+                return Err(e);
+            }
+        }
+        let file = open_file(new_data_path)?;
+        let file_clone = file.try_clone()?;
+        self.reader = Arc::new(Mutex::new(BufReader::new(file)));
+        self.writer = Arc::new(Mutex::new(data_writer(file_clone, &self.options)));
+        if self.background.is_some() {
+            self.background = Some(spawn_data_writer(
+                self.writer.clone(),
+                self.options.io_retries,
+            ));
+        }
+        self.path = PathBuf::from(new_data_path);
+        self.index.reopen(if single_file {
+            new_data_path
+        } else {
+            new_index_path
+        })
+    }
+    /// Copies the db file and the index file into `dir` (created if missing), keeping their
+    /// file names.
+    ///
+    /// Index writes deferred by (`DataBaseBuilder::coalesce_index_writes()`) or queued for
+    /// the index writer thread and values queued for the data writer thread are written
+    /// first, then both files are flushed and copied while holding the internal locks, so the
+    /// copies always match each other. Each copy is written to a temporary file first and
+    /// renamed into place, an existing backup is never left half-written.
+    /// # Example
+    /// ```
+    /// let mut db = mu_db::DataBase::new("./test_backup_to.db").unwrap();
+    /// db.clear_all().unwrap();
+    /// db.insert("key", "value");
+    /// db.backup_to(std::path::Path::new("./target/test_backup")).unwrap();
+    ///
+    /// let mut backup = mu_db::DataBase::new("./target/test_backup/test_backup_to.db").unwrap();
+    /// assert_eq!(backup.get("key"), Some("value".to_string()));
+    /// ```
+    /// Deferred index writes are part of the backup:
+    /// ```
+    /// use std::time::Duration;
+    /// let mut db = mu_db::DataBase::builder("./test_backup_coalesced.db")
+    ///     .coalesce_index_writes(Duration::from_secs(60), 1000)
+    ///     .open()
+    ///     .unwrap();
+    /// db.clear_all().unwrap();
+    /// db.insert("a", "1");
+    /// db.insert("b", "2");
+    /// db.backup_to(std::path::Path::new("./target/test_backup_coalesced")).unwrap();
+    ///
+    /// let backup = "./target/test_backup_coalesced/test_backup_coalesced.db";
+    /// let mut backup = mu_db::DataBase::new(backup).unwrap();
+    /// assert_eq!(backup.get("a"), Some("1".to_string()));
+    /// assert_eq!(backup.get("b"), Some("2".to_string()));
+    /// ```
+    pub fn backup_to(&mut self, dir: &Path) -> Result<()> {
+        std::fs::create_dir_all(dir)?;
+        self.index.flush()?;
+        wait_data_writer(&self.background)?;
+        let _r = self.reader.lock().unwrap();
+        let mut w = self.writer.lock().unwrap();
+        let mut iw = self.index.writer.lock().unwrap();
+        w.flush()?;
+        iw.flush()?;
+        let mut paths = vec![&self.path];
+        if self.index.path != self.path {
+            paths.push(&self.index.path);
+        }
+        for path in paths {
+            let name = path.file_name().ok_or_else(|| {
+                std::io::Error::new(std::io::ErrorKind::InvalidInput, "path has no file name")
+            })?;
+            let target = dir.join(name);
+            let mut tmp_name = name.to_os_string();
+            tmp_name.push(".tmp");
+            let tmp = dir.join(tmp_name);
+            std::fs::copy(path, &tmp)?;
+            std::fs::rename(&tmp, &target)?;
+        }
+        Ok(())
+    }
+    /// Writes all values, sorted by key and without gaps, to `data_out` and a matching index
+    /// to `index_out`, so the two together open as a compacted copy of the database. Tags and
+    /// versions are kept.
+    /// # Example
+    /// ```
+    /// use std::fs::File;
+    /// let mut db = mu_db::DataBase::new("./test_pack.db").unwrap();
+    /// db.clear_all().unwrap();
+    /// db.insert("b", "bb");
+    /// db.insert("a", "aaa");
+    /// db.remove("b").unwrap();
+    /// db.insert("c", "c");
+    /// db.pack(
+    ///     File::create("./test_packed.db").unwrap(),
+    ///     File::create("./index_test_packed.db").unwrap(),
+    /// )
+    /// .unwrap();
+    ///
+    /// let mut packed = mu_db::DataBase::new("./test_packed.db").unwrap();
+    /// assert_eq!(packed.buf_len(), 4);
+    /// assert_eq!(packed.read_at(0, 4).unwrap(), "aaac");
+    /// assert_eq!(packed.get("c"), Some("c".to_string()));
+    /// ```
+    pub fn pack(&mut self, mut data_out: impl Write, mut index_out: impl Write) -> Result<()> {
+        self.index.load_all()?;
+        let mut entries = self.index.get_all_entries();
+        entries.sort_by(|a, b| a.key.cmp(&b.key));
+        let mut buf = Vec::new();
+        let mut offset = 0;
+        for e in entries.iter_mut() {
+            let size = e.size();
+            buf.resize(size, 0);
+            self.read_into(e.range.start as u64, &mut buf)?;
+            data_out.write_all(&buf)?;
+            e.range = offset..offset + size;
+            e.reserved = 0;
+            offset += size;
+        }
+        data_out.flush()?;
+        let index = Index::entries_to_string(&entries, self.index.checksums);
+        index_out.write_all(index.as_bytes())?;
+        index_out.flush()
+    }
+    /// Writes the database to `w` as one self-contained stream that (`DataBase::open_frozen()`)
+    /// reads back: the values as written by (`.pack()`), followed by the matching index text,
+    /// its length (`u64` little endian) and [FROZEN_MAGIC].
+    /// # Example
+    /// ```
+    /// let mut db = mu_db::DataBase::new("./test_freeze.db").unwrap();
+    /// db.clear_all().unwrap();
+    /// db.insert("b", "bee");
+    /// db.insert("a", "ant");
+    /// let mut blob = Vec::new();
+    /// db.freeze(&mut blob).unwrap();
+    ///
+    /// // e.g. `include_bytes!("table.frozen")`
+    /// let mut frozen = mu_db::DataBase::open_frozen(std::io::Cursor::new(&blob[..])).unwrap();
+    /// assert_eq!(frozen.len(), 2);
+    /// assert_eq!(frozen.get("b").unwrap(), Some("bee".to_string()));
+    /// assert_eq!(frozen.get("c").unwrap(), None);
+    /// ```
+    pub fn freeze<W: Write>(&mut self, w: &mut W) -> Result<()> {
+        let mut table = Vec::new();
+        self.pack(&mut *w, &mut table)?;
+        w.write_all(&table)?;
+        w.write_all(&(table.len() as u64).to_le_bytes())?;
+        w.write_all(FROZEN_MAGIC)?;
+        w.flush()
+    }
+    /// Opens a stream written by (`.freeze()`) for reading, see [FrozenDataBase].
+    pub fn open_frozen<R: Read + Seek>(mut r: R) -> Result<FrozenDataBase<R>> {
+        let invalid =
+            |msg: &str| std::io::Error::new(std::io::ErrorKind::InvalidData, msg.to_string());
+        let len = r.seek(SeekFrom::End(0))?;
+        if len < 16 {
+            return Err(invalid("frozen database is too short"));
+        }
+        let mut footer = [0; 16];
+        r.seek(SeekFrom::Start(len - 16))?;
+        r.read_exact(&mut footer)?;
+        match footer_version(&footer[8..], FROZEN_MAGIC) {
+            None => return Err(invalid("not a frozen database")),
+            Some(found) if found != FORMAT_VERSION => {
+                return Err(VersionMismatch { path: None, found }.into())
+            }
+            Some(_) => {}
+        }
+        let table_len = u64::from_le_bytes(footer[..8].try_into().unwrap());
+        if table_len > len - 16 {
+            return Err(invalid("frozen database offset table is truncated"));
+        }
+        let mut table = String::new();
+        r.seek(SeekFrom::Start(len - 16 - table_len))?;
+        r.by_ref().take(table_len).read_to_string(&mut table)?;
+        let mut entries = Index::parse_index(table)?;
+        entries.sort_by(|a, b| a.key.cmp(&b.key));
+        Ok(FrozenDataBase { entries, reader: r })
+    }
+    /// Compares the keys and values of two databases, see [Diff].
+    ///
+    /// Only the values of keys present in both are read, the first read error is returned.
+    /// # Example
+    /// ```
+    /// let mut a = mu_db::DataBase::new("./test_diff_a.db").unwrap();
+    /// let mut b = mu_db::DataBase::new("./test_diff_b.db").unwrap();
+    /// a.clear_all().unwrap();
+    /// b.clear_all().unwrap();
+    /// a.insert("same", "1");
+    /// b.insert("same", "1");
+    /// a.insert("changed", "old");
+    /// b.insert("changed", "new");
+    /// a.insert("removed", "x");
+    /// b.insert("added", "y");
+    /// let diff = a.diff(&mut b).unwrap();
+    /// assert_eq!(diff.only_in_self, vec!["removed"]);
+    /// assert_eq!(diff.only_in_other, vec!["added"]);
+    /// assert_eq!(diff.different, vec!["changed"]);
+    /// assert!(!diff.is_empty());
+    /// ```
+    pub fn diff(&mut self, other: &mut DataBase) -> Result<Diff> {
+        self.index.load_all()?;
+        other.index.load_all()?;
+        let mut diff = Diff::default();
+        for e in self.index.entries.iter() {
+            match other.index.get_entry(&e.key) {
+                None => diff.only_in_self.push(e.key.clone()),
+                Some(o) => {
+                    if self.fetch_value(e)? != other.fetch_value(&o)? {
+                        diff.different.push(e.key.clone());
+                    }
+                }
+            }
+        }
+        for e in other.index.entries.iter() {
+            if self.index.position(&e.key).is_none() {
+                diff.only_in_other.push(e.key.clone());
+            }
+        }
+        diff.only_in_self.sort();
+        diff.only_in_other.sort();
+        diff.different.sort();
+        Ok(diff)
+    }
+    /// Inserts every key of `other` into this database, a key present in both is resolved by
+    /// `on_conflict`. Returns the number of keys written.
+    ///
+    /// `other` is read in file offset order and its values are written with one (`.apply()`),
+    /// so they are held in memory until then. Values are copied as bytes, binary ones
+    /// included. With [ConflictPolicy::Error] an `AlreadyExists` error naming the first common
+    /// key is returned before anything is written. Only values are copied, not tags or content
+    /// types.
+    /// # Example
+    /// ```
+    /// use mu_db::ConflictPolicy;
+    /// let mut a = mu_db::DataBase::new("./test_merge_from_a.db").unwrap();
+    /// let mut b = mu_db::DataBase::new("./test_merge_from_b.db").unwrap();
+    /// a.clear_all().unwrap();
+    /// b.clear_all().unwrap();
+    /// a.insert("shared", "from a");
+    /// b.insert("shared", "from b");
+    /// b.insert("only_b", "b");
+    /// assert!(a.merge_from(&mut b, ConflictPolicy::Error).is_err());
+    /// assert_eq!(a.get("only_b"), None);
+    /// assert_eq!(a.merge_from(&mut b, ConflictPolicy::KeepSelf).unwrap(), 1);
+    /// assert_eq!(a.get("shared"), Some("from a".to_string()));
+    /// assert_eq!(a.merge_from(&mut b, ConflictPolicy::TakeOther).unwrap(), 2);
+    /// assert_eq!(a.get("shared"), Some("from b".to_string()));
+    /// assert_eq!(a.get("only_b"), Some("b".to_string()));
+    ///
+    /// b.insert_bytes("binary", &[0xff, 0xfe, 0x00]);
+    /// a.merge_from(&mut b, ConflictPolicy::TakeOther).unwrap();
+    /// assert_eq!(a.get_bytes("binary"), Some(vec![0xff, 0xfe, 0x00]));
+    /// ```
+    pub fn merge_from(
+        &mut self,
+        other: &mut DataBase,
+        on_conflict: ConflictPolicy,
+    ) -> Result<usize> {
+        other.index.load_all()?;
+        let mut ops = Vec::new();
+        for e in other.index.entries.iter() {
+            if self.index.contains(&e.key) {
+                match on_conflict {
+                    ConflictPolicy::KeepSelf => continue,
+                    ConflictPolicy::TakeOther => {}
+                    ConflictPolicy::Error => {
+                        return Err(std::io::Error::new(
+                            std::io::ErrorKind::AlreadyExists,
+                            format!("key `{}` exists in both databases", e.key),
+                        ))
+                    }
+                }
+            }
+            ops.push((e.key.as_str(), other.fetch_value_bytes(e)?));
+        }
+        let raw: Vec<(&str, Option<&[u8]>)> = ops
+            .iter()
+            .map(|(key, value)| (*key, Some(value.as_slice())))
+            .collect();
+        self.apply_raw(&raw)?;
+        Ok(ops.len())
+    }
+    /// Captures the current index so values can be read as of now while the database keeps
+    /// changing, see [Snapshot].
+    /// # Example
+    /// ```
+    /// let mut db = mu_db::DataBase::new("./test_snapshot.db").unwrap();
+    /// db.clear_all().unwrap();
+    /// db.insert("k1", "old");
+    /// db.insert("k2", "x");
+    /// let snapshot = db.snapshot();
+    /// db.insert("k1", "new value");
+    /// db.insert("k3", "added");
+    /// assert_eq!(snapshot.get("k1"), Some("old".to_string()));
+    /// assert_eq!(snapshot.get("k3"), None);
+    /// assert_eq!(db.get("k1"), Some("new value".to_string()));
+    /// ```
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            entries: self.index.get_all_entries(),
+            reader: self.reader.clone(),
+            writer: self.writer.clone(),
+            codec: self.options.codec.clone(),
+            background: self.background.clone(),
+        }
+    }
+    /// Returns `true` if `self.index.entries` is empty, and `false` otherwise.
+    ///
+    /// If you want to know if db file is empty, use (`.is_buf_empty()`).
+    /// # Example
+    /// ```
+    /// let mut db = mu_db::DataBase::new("./test.db").unwrap();
+    /// db.clear_all();
+    /// db.insert("key", "value");
+    /// assert!(!db.is_empty());
+    /// assert!(!db.is_buf_empty());
+    /// db.remove("key").unwrap();
+    /// assert!(db.is_empty());
+    /// assert!(!db.is_buf_empty());
+    /// db.shrink();
+    /// assert!(db.is_empty());
+    /// assert!(db.is_buf_empty());
+    /// ```
+    ///
+    pub fn is_empty(&self) -> bool {
+        self.index.is_empty()
+    }
+    /// Returns all keys in file offset order, borrowed from the index.
+    /// # Example
+    /// ```
+    /// let mut db = mu_db::DataBase::new("./test_keys.db").unwrap();
+    /// db.clear_all().unwrap();
+    /// db.insert("k1", "a");
+    /// db.insert("k2", "b");
+    /// assert_eq!(db.keys().collect::<Vec<_>>(), vec!["k1", "k2"]);
+    /// ```
+    pub fn keys(&self) -> impl Iterator<Item = &str> {
+        self.index.keys()
+    }
+    /// Returns the number of keys, from the index without any I/O.
+    /// # Example
+    /// ```
+    /// let mut db = mu_db::DataBase::new("./test_len.db").unwrap();
+    /// db.clear_all().unwrap();
+    /// db.insert("k1", "a");
+    /// db.insert("k2", "b");
+    /// db.insert("k1", "c");
+    /// assert_eq!(db.len(), 2);
+    /// ```
+    pub fn len(&self) -> usize {
+        self.index.len()
+    }
+    /// Returns `true` if db file has metadata length of 0, and `false` otherwise.
+    /// # Example
+    /// ```
+    /// let mut db = mu_db::DataBase::new("./test.db").unwrap();
+    /// db.clear_all();
+    /// assert!(db.is_buf_empty());
+    /// db.insert("key", "value");
+    /// assert!(!db.is_buf_empty());
+    /// ```
+    pub fn is_buf_empty(&self) -> bool {
+        self.buf_len() == 0
+    }
+    /// Returns the length of the db file, counting writes that are still buffered. It's
+    /// tracked in memory, so the file isn't touched.
+    /// # Example
+    /// ```
+    /// let mut db = mu_db::DataBase::new("./test_buf_len.db").unwrap();
+    /// db.clear_all();
+    /// db.insert("key", "value");
+    /// assert_eq!(db.buf_len(), 5);
+    /// db.clear_all();
+    /// assert_eq!(db.buf_len(), 0);
+    ///
+    /// // buffered writes count without being flushed:
+    /// db.set_auto_flush(false).unwrap();
+    /// db.insert("key", "abc");
+    /// assert_eq!(db.buf_len(), 3);
+    /// assert_eq!(std::fs::metadata("./test_buf_len.db").unwrap().len(), 0);
+    /// ```
+    pub fn buf_len(&self) -> u64 {
+        self.index.embedded_len().unwrap_or(self.data_len)
+    }
+    /// Sets the length of the database file directly, truncating or extending it as necessary.
+    /// # Example
+    /// ```
+    /// let mut db = mu_db::DataBase::new("./test.db").unwrap();
+    /// db.clear_all();
+    /// assert!(db.is_buf_empty());
+    /// assert_eq!(db.buf_len(), 0);
+    /// db.insert("key", "value");
+    /// assert_eq!(db.buf_len(), 5);
+    /// assert!(!db.is_buf_empty());
+    /// db.set_buf_len(0);
+    /// assert_eq!(db.buf_len(), 0);
+    /// assert!(db.is_buf_empty());
+    /// ```
+    pub fn set_buf_len(&mut self, len: u64) {
+        wait_data_writer(&self.background).unwrap();
+        let mut binding_r = self.reader.lock().unwrap();
+        let mut binding_w = self.writer.lock().unwrap();
+        binding_w.flush().unwrap();
+        let r = binding_r.get_mut();
+        let w = binding_w.get_mut();
+        r.seek(SeekFrom::Start(0)).unwrap();
+        w.seek(SeekFrom::Start(0)).unwrap();
+        r.set_len(len).unwrap();
+        w.set_len(len).unwrap();
+        drop(binding_r);
+        drop(binding_w);
+        self.data_len = len;
+        self.index.set_embedded_len(len).unwrap();
+    }
+    /// Replaces every value with the result of `f(key, value)`, writing the index once at the end.
+    ///
+    /// New values that fit in the old range are written in place, larger ones are relocated.
+    /// # Example
+    /// ```
+    /// let mut db = mu_db::DataBase::new("./test_map_values.db").unwrap();
+    /// db.clear_all().unwrap();
+    /// db.insert("k1", "hello");
+    /// db.insert("k2", "world");
+    /// db.map_values(|key, value| format!("{}:{}", key, value.to_uppercase()))
+    ///     .unwrap();
+    /// assert_eq!(db.get("k1"), Some("k1:HELLO".to_string()));
+    /// assert_eq!(db.get("k2"), Some("k2:WORLD".to_string()));
+    /// ```
+    pub fn map_values(&mut self, mut f: impl FnMut(&str, &str) -> String) -> Result<()> {
+        self.index.load_all()?;
+        let mut result = Ok(());
+        for old in self.index.get_all_entries() {
+            let value = match self.fetch_value(&old) {
+                Ok(v) => v,
+                Err(e) => {
+                    result = Err(e);
+                    break;
+                }
+            };
+            let new_value = f(&old.key, &value);
+            if new_value == value {
+                continue;
+            }
+            let new_value = self.encode(new_value.as_bytes()).into_owned();
+            let entry = self.index.place_entry(new_value.len(), &old.key);
+            if let Err(e) = self.write_bytes(entry.range.start as u64, &new_value) {
+                result = Err(e);
+                break;
+            }
+        }
+        self.index.write_index();
+        result
+    }
+    /// Returns each key and its value in index order, which is file offset order (not insertion
+    /// or key order). Values are read one at a time as the iterator advances, see [Iter]. The
+    /// iterator borrows the database, so it can't be changed during the iteration.
+    ///
+    /// Panics if a value can't be read, see (`.try_entries()`).
+    /// # Example
+    /// ```
+    /// let mut db = mu_db::DataBase::new("./test_iter.db").unwrap();
+    /// db.clear_all().unwrap();
+    /// db.insert("k1", "hello");
+    /// db.insert("k2", "world");
+    /// let pairs: Vec<(String, String)> = db.iter().collect();
+    /// assert_eq!(pairs[1], ("k2".to_string(), "world".to_string()));
+    /// ```
+    pub fn iter(&mut self) -> Iter<'_> {
+        self.index.load_all().unwrap();
+        self.flush_pending().unwrap();
+        Iter {
+            entries: self.index.get_all_entries().into_iter(),
+            reader: self.reader.lock().unwrap(),
+            pos: None,
+            codec: self.options.codec.as_deref(),
+        }
+    }
+    /// Returns each key and its value in file offset order. A value that can't be read (e.g.
+    /// its range is past the end of a truncated db file) is an `Err` item, the iteration goes
+    /// on with the next entry.
+    /// # Example
+    /// ```
+    /// let mut db = mu_db::DataBase::new("./test_try_entries.db").unwrap();
+    /// db.clear_all().unwrap();
+    /// db.insert("k1", "hello");
+    /// db.insert("k2", "world");
+    /// db.set_buf_len(7);
+    /// let (ok, err): (Vec<_>, Vec<_>) = db.try_entries().partition(|r| r.is_ok());
+    /// assert_eq!(ok[0].as_ref().unwrap(), &("k1".to_string(), "hello".to_string()));
+    /// assert_eq!(err.len(), 1);
+    /// ```
+    pub fn try_entries(&mut self) -> impl Iterator<Item = Result<(String, String)>> + '_ {
+        let loaded = self.index.load_all();
+        let entries = self.index.get_all_entries();
+        loaded
+            .err()
+            .map(Err)
+            .into_iter()
+            .chain(entries.into_iter().map(move |e| {
+                let value = self.fetch_value(&e)?;
+                Ok((e.key, value))
+            }))
+    }
+    /// Calls `f` with each key and its raw value, in file offset order.
+    ///
+    /// The value is read into one buffer reused for all entries, so it's only valid until
+    /// `f` returns.
+    /// # Example
+    /// ```
+    /// let mut db = mu_db::DataBase::new("./test_for_each_bytes.db").unwrap();
+    /// db.clear_all().unwrap();
+    /// db.insert("k1", "hello");
+    /// db.insert("k2", "world!");
+    /// let mut total = 0;
+    /// db.for_each_bytes(|_, value| total += value.len()).unwrap();
+    /// assert_eq!(total, 11);
+    /// ```
+    pub fn for_each_bytes<F: FnMut(&str, &[u8])>(&mut self, mut f: F) -> Result<()> {
+        self.index.load_all()?;
+        let mut buf = Vec::new();
+        for e in self.index.entries.iter() {
+            if let Some(value) = &e.inline {
+                f(&e.key, value.as_bytes());
+                continue;
+            }
+            buf.resize(e.size(), 0);
+            self.read_into(e.range.start as u64, &mut buf)?;
+            f(&e.key, &buf);
+        }
+        Ok(())
+    }
+    /// Reads the values of many keys at once, replacing the content of `results` with one
+    /// item per key in `keys` order (`None` for missing keys).
+    ///
+    /// The reads are done in file offset order under a single reader lock.
+    /// # Example
+    /// ```
+    /// let mut db = mu_db::DataBase::new("./test_read_batch.db").unwrap();
+    /// db.clear_all().unwrap();
+    /// db.insert("k1", "hello");
+    /// db.insert("k2", "world");
+    /// let mut results = Vec::new();
+    /// db.read_batch(&["k2", "missing", "k1"], &mut results).unwrap();
+    /// assert_eq!(
+    ///     results,
+    ///     vec![Some(b"world".to_vec()), None, Some(b"hello".to_vec())]
+    /// );
+    /// ```
+    pub fn read_batch(&mut self, keys: &[&str], results: &mut Vec<Option<Vec<u8>>>) -> Result<()> {
+        results.clear();
+        results.resize(keys.len(), None);
+
+        let mut ranges: Vec<(usize, Range<usize>)> = Vec::new();
+        for (i, key) in keys.iter().enumerate() {
+            if let Some(e) = self.index.get_entry(key) {
+                match e.inline {
+                    Some(value) => results[i] = Some(value.into_bytes()),
+                    None => ranges.push((i, e.range)),
+                }
+            }
+        }
+        ranges.sort_by_key(|(_, r)| r.start);
+
+        self.flush_pending()?;
+        let mut br = self.reader.lock().unwrap();
+        for (i, range) in ranges {
+            let mut v = vec![0; range.end - range.start];
+            br.seek(SeekFrom::Start(range.start as u64))?;
+            br.read_exact(&mut v)?;
+            results[i] = Some(v);
+        }
+        Ok(())
+    }
+    /// Returns the keys whose value contains `needle`.
+    ///
+    /// This is a full scan, values are read in file offset order.
+    /// # Example
+    /// ```
+    /// let mut db = mu_db::DataBase::new("./test_search_values.db").unwrap();
+    /// db.clear_all().unwrap();
+    /// db.insert("k1", "hello world");
+    /// db.insert("k2", "goodbye");
+    /// db.insert("k3", "world peace");
+    /// assert_eq!(db.search_values("world"), vec!["k1", "k3"]);
+    /// ```
+    pub fn search_values(&mut self, needle: &str) -> Vec<String> {
+        self.index.load_all().unwrap();
+        let mut keys = Vec::new();
+        for e in self.index.get_all_entries() {
+            let value = match &e.inline {
+                Some(value) => value.clone(),
+                None => self.read_at(e.range.start as u64, e.size()).unwrap(),
+            };
+            if value.contains(needle) {
+                keys.push(e.key);
+            }
+        }
+        keys
+    }
+    /// Attaches `tag` to the entry of `key`, returns `false` if the key doesn't exist.
+    ///
+    /// Tags are stored with the entry in the index file and survive overwrites of the value.
+    /// # Example
+    /// ```
+    /// let mut db = mu_db::DataBase::new("./test_tags.db").unwrap();
+    /// db.clear_all().unwrap();
+    /// db.insert("k1", "a");
+    /// db.insert("k2", "b");
+    /// assert!(db.add_tag("k1", "archived"));
+    /// assert!(db.add_tag("k2", "archived"));
+    /// assert!(db.add_tag("k2", "a,b=c"));
+    /// assert!(!db.add_tag("missing", "archived"));
+    /// assert_eq!(db.keys_with_tag("archived"), vec!["k1", "k2"]);
+    ///
+    /// let mut db = mu_db::DataBase::new("./test_tags.db").unwrap();
+    /// assert_eq!(db.tags_of("k2"), Some(vec!["archived".to_string(), "a,b=c".to_string()]));
+    /// db.remove("k1").unwrap();
+    /// assert_eq!(db.keys_with_tag("archived"), vec!["k2"]);
+    /// assert!(db.remove_tag("k2", "archived"));
+    /// assert!(db.keys_with_tag("archived").is_empty());
+    /// ```
+    pub fn add_tag(&mut self, key: &str, tag: &str) -> bool {
+        self.index.add_tag(key, tag)
+    }
+    /// Detaches `tag` from the entry of `key`, returns `false` if the entry didn't have it.
+    pub fn remove_tag(&mut self, key: &str, tag: &str) -> bool {
+        self.index.remove_tag(key, tag)
+    }
+    /// Returns the tags of `key`, or `None` if the key doesn't exist.
+    pub fn tags_of(&self, key: &str) -> Option<Vec<String>> {
+        self.index.get_entry(key).map(|e| e.tags)
+    }
+    /// Returns the keys tagged with `tag`, sorted.
+    pub fn keys_with_tag(&self, tag: &str) -> Vec<String> {
+        self.index.keys_with_tag(tag)
+    }
+    /// Returns up to `limit` keys in sorted order after `cursor` (from the first key if
+    /// `None`), and the cursor of the next page if there are more keys.
+    ///
+    /// With [KeyLookup::BinarySearch] a page costs O(log n + limit), otherwise the keys are
+    /// scanned and sorted for every page.
+    /// # Example
+    /// ```
+    /// let mut db = mu_db::DataBase::new("./test_list.db").unwrap();
+    /// db.clear_all().unwrap();
+    /// for k in ["d", "a", "c", "e", "b"] {
+    ///     db.insert(k, k);
+    /// }
+    /// let (page, cursor) = db.list(None, 2);
+    /// assert_eq!(page, vec!["a", "b"]);
+    /// db.remove("b").unwrap();
+    /// let (page, cursor) = db.list(cursor, 2);
+    /// assert_eq!(page, vec!["c", "d"]);
+    /// let (page, cursor) = db.list(cursor, 2);
+    /// assert_eq!(page, vec!["e"]);
+    /// assert_eq!(cursor, None);
+    /// ```
+    pub fn list(&self, cursor: Option<Cursor>, limit: usize) -> (Vec<String>, Option<Cursor>) {
+        let after = cursor.as_ref().map(|c| c.last_key.as_str());
+        let (keys, more) = self.index.keys_after(after, limit);
+        let next = if more {
+            keys.last().map(|k| Cursor::after(k))
+        } else {
+            None
+        };
+        (keys, next)
+    }
+    /// Returns where the value of `key` is in the db file, or `None` if the key doesn't
+    /// exist, without cloning the index entry.
+    /// # Example
+    /// ```
+    /// let mut db = mu_db::DataBase::new("./test_head.db").unwrap();
+    /// db.clear_all().unwrap();
+    /// db.insert("k1", "hello");
+    /// db.insert("k2", "world!");
+    /// let head = db.head("k2").unwrap();
+    /// assert_eq!((head.start, head.size), (5, 6));
+    /// assert_eq!(db.read_at(head.start as u64, 3).unwrap(), "wor");
+    /// assert_eq!(db.head("missing"), None);
+    /// ```
+    pub fn head(&self, key: &str) -> Option<Head> {
+        self.index.head(key)
+    }
+    /// Returns the key and range of the entry whose value holds the byte at `offset` of the db
+    /// file, or `None` if the byte is free (e.g. left by a removed value). Useful together with
+    /// (`.read_at()`) to see which key a byte belongs to.
+    /// # Example
+    /// ```
+    /// let mut db = mu_db::DataBase::new("./test_entry_at_offset.db").unwrap();
+    /// db.clear_all().unwrap();
+    /// db.insert("k1", "hello");
+    /// db.insert("k2", "world");
+    /// db.insert("k3", "!");
+    /// assert_eq!(db.entry_at_offset(7), Some(("k2".to_string(), 5..10)));
+    /// db.remove("k2").unwrap();
+    /// assert_eq!(db.entry_at_offset(7), None);
+    /// assert_eq!(db.entry_at_offset(10), Some(("k3".to_string(), 10..11)));
+    /// assert_eq!(db.entry_at_offset(11), None);
+    /// ```
+    pub fn entry_at_offset(&self, offset: usize) -> Option<(String, Range<usize>)> {
+        self.index.entry_at_offset(offset)
+    }
+    /// Inserts like (`.insert()`) and stores `content_type` (e.g. a MIME type) in the index
+    /// entry of `key`. A later (`.insert()`) keeps the content type.
+    /// # Example
+    /// ```
+    /// let mut db = mu_db::DataBase::new("./test_content_type.db").unwrap();
+    /// db.clear_all().unwrap();
+    /// db.insert_with_content_type("page", "<p>hi</p>", "text/html; charset=utf-8");
+    /// db.insert("plain", "hi");
+    /// assert_eq!(db.get("page"), Some("<p>hi</p>".to_string()));
+    /// assert_eq!(db.content_type_of("plain"), None);
+    ///
+    /// let db = mu_db::DataBase::new("./test_content_type.db").unwrap();
+    /// assert_eq!(
+    ///     db.content_type_of("page"),
+    ///     Some("text/html; charset=utf-8".to_string())
+    /// );
+    /// ```
+    pub fn insert_with_content_type(&mut self, key: &str, value: &str, content_type: &str) {
+        self.insert(key, value);
+        self.index.set_content_type(key, Some(content_type));
+    }
+    /// Returns the content type of `key`, or `None` if the key doesn't exist or has none.
+    pub fn content_type_of(&self, key: &str) -> Option<String> {
+        self.index.get_entry(key)?.content_type
+    }
+    /// Returns the version of `key`, or `None` if the key doesn't exist.
+    ///
+    /// The version starts at 1 when a key is inserted and is incremented by every write to
+    /// it, it is stored in the index file. A removed and reinserted key starts over at 1.
+    /// # Example
+    /// ```
+    /// let mut db = mu_db::DataBase::new("./test_version_of.db").unwrap();
+    /// db.clear_all().unwrap();
+    /// assert_eq!(db.version_of("key"), None);
+    /// db.insert("key", "a");
+    /// assert_eq!(db.version_of("key"), Some(1));
+    /// db.insert("key", "b");
+    /// db.insert("key", "longer");
+    /// assert_eq!(db.version_of("key"), Some(3));
+    ///
+    /// let db = mu_db::DataBase::new("./test_version_of.db").unwrap();
+    /// assert_eq!(db.version_of("key"), Some(3));
+    /// ```
+    pub fn version_of(&self, key: &str) -> Option<u64> {
+        self.index.get_entry(key).map(|e| e.version)
+    }
+    /// Rewrites the whole index file from the in-memory index.
+    ///
+    /// Useful to restore an index file that was modified or damaged outside of the database.
+    /// # Example
+    /// ```
+    /// let mut db = mu_db::DataBase::new("./test_rewrite_index.db").unwrap();
+    /// db.clear_all().unwrap();
+    /// db.insert("key", "value");
+    /// std::fs::write("./index_test_rewrite_index.db", "garbage").unwrap();
+    /// db.rewrite_index().unwrap();
+    /// let mut db = mu_db::DataBase::new("./test_rewrite_index.db").unwrap();
+    /// assert_eq!(db.get("key"), Some("value".to_string()));
+    /// ```
+    pub fn rewrite_index(&mut self) -> Result<()> {
+        self.index.try_write_index()
+    }
+    /// Returns how many times the index was written since the database was opened, see
+    /// (`DataBaseBuilder::coalesce_index_writes()`).
+    pub fn index_writes(&self) -> u64 {
+        self.index.writes()
+    }
+    /// Returns an estimate of the bytes held in memory by the index.
+    ///
+    /// Counts the entries vec allocation plus the capacity of every key string, it
+    /// doesn't include allocator overhead.
+    /// # Example
+    /// ```
+    /// let mut db = mu_db::DataBase::new("./test_index_memory_usage.db").unwrap();
+    /// db.clear_all().unwrap();
+    /// let empty = db.index_memory_usage();
+    /// db.insert("key", "value");
+    /// assert!(db.index_memory_usage() > empty);
+    /// ```
+    pub fn index_memory_usage(&self) -> usize {
+        self.index.memory_usage()
+    }
+    /// Releases spare capacity held by the in-memory index.
+    ///
+    /// The whole index is kept in memory unless [Options::max_index_entries] is set, after
+    /// removing many keys the entries vec keeps its capacity until this is called.
+    /// # Example
+    /// ```
+    /// let mut db = mu_db::DataBase::new("./test_shrink_index_to_fit.db").unwrap();
+    /// db.clear_all().unwrap();
+    /// for i in 0..100 {
+    ///     db.insert(&i.to_string(), "v");
+    /// }
+    /// for i in 0..100 {
+    ///     db.remove(&i.to_string()).unwrap();
+    /// }
+    /// let before = db.index_memory_usage();
+    /// db.shrink_index_to_fit();
+    /// assert!(db.index_memory_usage() < before);
+    /// ```
+    pub fn shrink_index_to_fit(&mut self) {
+        self.index.shrink_to_fit();
+    }
+    /// Loads every index entry into memory, so the methods taking `&self` see all of them.
+    ///
+    /// Only does something with [Options::max_index_entries], the entries are dropped again
+    /// by a later operation on a key. Fails if the index file can't be read.
+    /// # Example
+    /// ```
+    /// let mut db = mu_db::DataBase::builder("./test_load_index.db")
+    ///     .max_index_entries(4)
+    ///     .open()
+    ///     .unwrap();
+    /// db.clear_all().unwrap();
+    /// for i in 0..100 {
+    ///     db.insert(&i.to_string(), "v");
+    /// }
+    /// db.load_index().unwrap();
+    /// assert_eq!(db.keys().count(), 100);
+    /// ```
+    pub fn load_index(&mut self) -> Result<()> {
+        self.index.load_all()
+    }
+    /// Returns the serialized index, the same bytes the index file holds.
+    /// # Example
+    /// ```
+    /// let mut db = mu_db::DataBase::new("./test_index_bytes.db").unwrap();
+    /// db.clear_all().unwrap();
+    /// db.insert("key", "value");
+    /// assert_eq!(db.index_bytes().unwrap(), b"key=0_5=version:1\n");
+    /// assert_eq!(
+    ///     db.index_bytes().unwrap(),
+    ///     std::fs::read("./index_test_index_bytes.db").unwrap()
+    /// );
+    /// ```
+    pub fn index_bytes(&self) -> Result<Vec<u8>> {
+        Ok(self.index.file_bytes())
+    }
+    /// Checks the index against itself and the db file, returns every issue found.
+    /// # Example
+    /// ```
+    /// let mut db = mu_db::DataBase::new("./test_verify.db").unwrap();
+    /// db.clear_all().unwrap();
+    /// db.insert("key", "value");
+    /// assert!(db.verify().is_empty());
+    /// db.set_buf_len(3);
+    /// assert_eq!(
+    ///     db.verify(),
+    ///     vec![mu_db::Issue::OutOfBounds { key: "key".to_string(), end: 5, buf_len: 3 }]
+    /// );
+    /// ```
+    /// Inline and empty values have nothing in the db file, so they are never out of bounds:
+    /// ```
+    /// let mut db = mu_db::DataBase::builder("./test_verify_inline.db")
+    ///     .reserve_prefix(3)
+    ///     .inline_threshold(5)
+    ///     .open()
+    ///     .unwrap();
+    /// db.clear_all().unwrap();
+    /// db.insert("k3", "ab");
+    /// assert!(db.verify().is_empty());
+    /// assert!(db.repair().dropped.is_empty());
+    /// assert_eq!(db.get("k3"), Some("ab".to_string()));
+    ///
+    /// let mut db = mu_db::DataBase::builder("./test_verify_empty.db")
+    ///     .reserve_prefix(3)
+    ///     .open()
+    ///     .unwrap();
+    /// db.clear_all().unwrap();
+    /// db.insert("k", "");
+    /// assert!(db.verify().is_empty());
+    /// assert!(db.repair().dropped.is_empty());
+    /// assert_eq!(db.get("k"), Some("".to_string()));
+    /// ```
+    pub fn verify(&self) -> Vec<Issue> {
+        let mut issues = Vec::new();
+        let buf_len = self.buf_len();
+        let mut seen = HashSet::new();
+        let mut prev: Option<&IndexEntry> = None;
+        for e in self.index.entries.iter() {
+            if !seen.insert(e.key.as_str()) {
+                issues.push(Issue::DuplicateKey(e.key.clone()));
+            }
+            if e.inline.is_some() {
+                continue;
+            }
+            if let Some(p) = prev {
+                if e.range.start < p.occupied_end() {
+                    issues.push(Issue::Overlap(p.key.clone(), e.key.clone()));
+                }
+            }
+            if !e.range.is_empty() && e.range.end as u64 > buf_len {
+                issues.push(Issue::OutOfBounds {
+                    key: e.key.clone(),
+                    end: e.range.end,
+                    buf_len,
+                });
+            }
+            prev = Some(e);
+        }
+        issues
+    }
+    /// Fixes the issues found by (`.verify()`) by dropping entries from the index: entries
+    /// past the end of the db file, all but the highest version (the last one on ties) of a
+    /// duplicate key, and the later of two overlapping entries. The values of dropped
+    /// entries are left in the db file.
+    /// # Example
+    /// ```
+    /// let mut db = mu_db::DataBase::new("./test_repair.db").unwrap();
+    /// db.clear_all().unwrap();
+    /// db.insert("key", "value");
+    /// db.set_buf_len(3);
+    /// let report = db.repair();
+    /// assert_eq!(report.dropped, vec!["key".to_string()]);
+    /// assert!(db.verify().is_empty());
+    /// ```
+    pub fn repair(&mut self) -> RepairReport {
+        self.index.load_all().unwrap();
+        let issues = self.verify();
+        let mut report = RepairReport {
+            issues,
+            ..RepairReport::default()
+        };
+        if report.issues.is_empty() {
+            return report;
+        }
+        let buf_len = self.buf_len();
+        let mut entries = Vec::new();
+        for e in self.index.get_all_entries() {
+            if e.inline.is_none() && !e.range.is_empty() && e.range.end as u64 > buf_len {
+                report.dropped.push(e.key);
+            } else {
+                entries.push(e);
+            }
+        }
+        let mut latest: HashMap<&str, usize> = HashMap::new();
+        for (i, e) in entries.iter().enumerate() {
+            let best = latest.entry(e.key.as_str()).or_insert(i);
+            if e.version >= entries[*best].version {
+                *best = i;
+            }
+        }
+        let keep: HashSet<usize> = latest.into_values().collect();
+        let mut deduped = Vec::new();
+        for (i, e) in entries.into_iter().enumerate() {
+            if keep.contains(&i) {
+                deduped.push(e);
+            } else {
+                report.dropped.push(e.key);
+            }
+        }
+        deduped.sort_by_key(|e| e.range.start);
+        let mut repaired: Vec<IndexEntry> = Vec::new();
+        // end of the last kept entry with its value in the db file:
+        let mut occupied_end = 0;
+        for e in deduped {
+            if e.inline.is_some() {
+                repaired.push(e);
+            } else if e.range.start < occupied_end {
+                report.dropped.push(e.key);
+            } else {
+                occupied_end = e.occupied_end();
+                repaired.push(e);
+            }
+        }
+        self.index.set_all_entries(repaired);
+        report
+    }
+    /// Returns the number of bytes in the db file not used by any entry, which (`.shrink()`)
+    /// would reclaim. The reserved prefix (`DataBaseBuilder::reserve_prefix()`) and bytes
+    /// reserved by [ShrinkTailPolicy::Keep] aren't reclaimable.
+    /// # Example
+    /// ```
+    /// let mut db = mu_db::DataBase::new("./test_reclaimable.db").unwrap();
+    /// db.clear_all().unwrap();
+    /// db.insert("k1", "hello");
+    /// db.insert("k2", "world");
+    /// db.remove("k1").unwrap();
+    /// assert_eq!(db.reclaimable_bytes(), 5);
+    ///
+    /// let mut db = mu_db::DataBase::builder("./test_reclaimable_reserved.db")
+    ///     .reserve_prefix(4)
+    ///     .shrink_tail(mu_db::ShrinkTailPolicy::Keep)
+    ///     .open()
+    ///     .unwrap();
+    /// db.clear_all().unwrap();
+    /// db.write_at(0, "HDR1").unwrap();
+    /// db.insert("k1", "1234567890");
+    /// db.insert("k1", "12345");
+    /// assert_eq!(db.reclaimable_bytes(), 0);
+    /// assert_eq!(db.fragmentation(), 0.0);
+    /// ```
+    pub fn reclaimable_bytes(&self) -> u64 {
+        let live: usize = self.index.entries.iter().map(|e| e.capacity()).sum();
+        let used = live + self.index.reserved_prefix;
+        self.buf_len().saturating_sub(used as u64)
+    }
+    /// Returns the fraction of the db file not used by any entry, (`.reclaimable_bytes()`)
+    /// divided by (`.buf_len()`), `0.0` for an empty file.
+    /// # Example
+    /// ```
+    /// let mut db = mu_db::DataBase::new("./test_fragmentation.db").unwrap();
+    /// db.clear_all().unwrap();
+    /// assert_eq!(db.fragmentation(), 0.0);
+    /// db.insert("k1", "hello");
+    /// db.insert("k2", "world");
+    /// db.remove("k1").unwrap();
+    /// assert_eq!(db.fragmentation(), 0.5);
+    /// ```
+    pub fn fragmentation(&self) -> f64 {
+        match self.buf_len() {
+            0 => 0.0,
+            len => self.reclaimable_bytes() as f64 / len as f64,
+        }
+    }
+    /// Returns the number of distinct free regions in the db file: before the first entry,
+    /// between entries and after the last one. Bytes reserved by [ShrinkTailPolicy::Keep]
+    /// aren't free.
+    /// # Example
+    /// ```
+    /// let mut db = mu_db::DataBase::new("./test_gap_count.db").unwrap();
+    /// db.clear_all().unwrap();
+    /// for k in ["k1", "k2", "k3", "k4"] {
+    ///     db.insert(k, "value");
+    /// }
+    /// assert_eq!(db.gap_count(), 0);
+    /// db.remove("k1").unwrap();
+    /// db.remove("k3").unwrap();
+    /// assert_eq!(db.gap_count(), 2);
+    /// db.remove("k2").unwrap();
+    /// assert_eq!(db.gap_count(), 1);
+    /// db.remove("k4").unwrap();
+    /// assert_eq!(db.gap_count(), 1);
+    /// ```
+    pub fn gap_count(&self) -> usize {
+        self.free_ranges().len()
+    }
+    /// Counts entries by value size: item `i` counts sizes up to `buckets[i]` (inclusive) not
+    /// counted by an earlier bucket, the extra last item counts sizes larger than every bound.
+    /// `buckets` must be sorted.
+    /// # Example
+    /// ```
+    /// let mut db = mu_db::DataBase::new("./test_size_histogram.db").unwrap();
+    /// db.clear_all().unwrap();
+    /// for (k, v) in [("a", "1"), ("b", "1234"), ("c", "12345"), ("d", "1234567890")] {
+    ///     db.insert(k, v);
+    /// }
+    /// assert_eq!(db.size_histogram(&[4, 8]), vec![2, 1, 1]);
+    /// // sizes up to 1, 2, 4, 8, 16
+    /// assert_eq!(db.size_histogram_pow2(), vec![1, 0, 1, 1, 1]);
+    /// ```
+    pub fn size_histogram(&self, buckets: &[usize]) -> Vec<usize> {
+        let mut counts = vec![0; buckets.len() + 1];
+        for e in self.index.entries.iter() {
+            counts[buckets.partition_point(|&b| b < e.size())] += 1;
+        }
+        counts
+    }
+    /// Same as (`.size_histogram()`) with power of two bounds `1, 2, 4, ...` up to the
+    /// largest value size, without the empty last item.
+    pub fn size_histogram_pow2(&self) -> Vec<usize> {
+        let max = self
+            .index
+            .entries
+            .iter()
+            .map(|e| e.size())
+            .max()
+            .unwrap_or(0);
+        let mut buckets = vec![1];
+        while *buckets.last().unwrap() < max {
+            buckets.push(buckets.last().unwrap() * 2);
+        }
+        let mut counts = self.size_histogram(&buckets);
+        counts.pop();
+        counts
+    }
+    /// Returns every entry in offset order with its key, range and the free bytes between
+    /// its end (including bytes reserved by [ShrinkTailPolicy::Keep]) and the next entry, or
+    /// the end of the db file for the last one. Free bytes before the first entry aren't
+    /// included, see (`.free_ranges()`).
+    /// # Example
+    /// ```
+    /// let mut db = mu_db::DataBase::new("./test_layout_report.db").unwrap();
+    /// db.clear_all().unwrap();
+    /// db.insert("k1", "hello");
+    /// db.insert("k2", "abc");
+    /// db.insert("k3", "world");
+    /// db.remove("k2").unwrap();
+    /// db.write_at(15, "x").unwrap();
+    /// assert_eq!(
+    ///     db.layout_report(),
+    ///     vec![("k1".to_string(), 0..5, 3), ("k3".to_string(), 8..13, 3)]
+    /// );
+    /// ```
+    pub fn layout_report(&self) -> Vec<(String, Range<usize>, usize)> {
+        let buf_len = self.buf_len() as usize;
+        let entries = &self.index.entries;
+        entries
+            .iter()
+            .enumerate()
+            .map(|(i, e)| {
+                let next = entries.get(i + 1).map_or(buf_len, |n| n.range.start);
+                let free = next.saturating_sub(e.occupied_end());
+                (e.key.clone(), e.range.clone(), free)
+            })
+            .collect()
+    }
+    /// Returns the free regions of the db file in offset order, see (`.gap_count()`).
+    ///
+    /// Free space is the space between entries, so regions freed next to each other (e.g. the
+    /// tail of a shrinking overwrite and a removed neighbour) are always one region.
+    /// # Example
+    /// ```
+    /// let mut db = mu_db::DataBase::new("./test_free_ranges.db").unwrap();
+    /// db.clear_all().unwrap();
+    /// db.insert("k1", "1234567890");
+    /// db.insert("k2", "abc");
+    /// db.insert("k3", "x");
+    /// db.insert("k1", "12345");
+    /// assert_eq!(db.free_ranges(), vec![5..10]);
+    /// db.remove("k2").unwrap();
+    /// assert_eq!(db.free_ranges(), vec![5..13]);
+    /// db.insert("k4", "12345678"); // fits in the merged region
+    /// assert!(db.free_ranges().is_empty());
+    /// assert_eq!(db.buf_len(), 14);
+    /// ```
+    pub fn free_ranges(&self) -> Vec<Range<u64>> {
+        let mut ranges = Vec::new();
+        let mut end = self.index.reserved_prefix;
+        for e in self.index.entries.iter() {
+            if e.range.start > end {
+                ranges.push(end as u64..e.range.start as u64);
             }
-            for i in 0..self.entries.len() - 1 {
-                if (self.entries[i + 1].range.start - self.entries[i].range.end) >= entry_size {
-                    let bind = &self.entries[i];
-                    let entry = IndexEntry {
-                        key: key.to_string(),
-                        range: bind.range.end..bind.range.end + entry_size,
-                    };
-                    self.entries.insert(i + 1, entry.clone());
-                    self.write_index();
-                    return entry;
-                }
+            end = e.occupied_end();
+        }
+        let buf_len = self.buf_len();
+        if buf_len > end as u64 {
+            ranges.push(end as u64..buf_len);
+        }
+        ranges
+    }
+    /// Returns the number of bytes at the end of the db file past the last entry.
+    ///
+    /// Gaps between entries are reused by later inserts, but trailing bytes (left by
+    /// (`.write_at()`), removing the last entry or an interrupted session) only count towards
+    /// the file length until (`.reclaim_orphans()`) or (`.shrink()`) truncates them.
+    /// # Example
+    /// ```
+    /// let mut db = mu_db::DataBase::new("./test_orphans.db").unwrap();
+    /// db.clear_all().unwrap();
+    /// db.insert("k1", "hello");
+    /// db.write_at(10, "junk").unwrap();
+    /// assert_eq!(db.orphaned_bytes(), 9);
+    /// db.reclaim_orphans();
+    /// assert_eq!(db.orphaned_bytes(), 0);
+    /// assert_eq!(db.buf_len(), 5);
+    /// assert_eq!(db.get("k1"), Some("hello".to_string()));
+    /// ```
+    pub fn orphaned_bytes(&self) -> usize {
+        let end = self.index.data_end();
+        (self.buf_len() as usize).saturating_sub(end)
+    }
+    /// Truncates the db file right after the last entry, see (`.orphaned_bytes()`).
+    pub fn reclaim_orphans(&mut self) {
+        let end = self.index.data_end();
+        if (end as u64) < self.buf_len() {
+            self.set_buf_len(end as u64);
+        }
+    }
+    /// Summarizes (`.verify()`) and (`.reclaimable_bytes()`) into a recommended action.
+    ///
+    /// The database is considered fragmented once reclaimable bytes make up at least
+    /// `SHRINK_RECOMMENDED_RATIO` of the db file.
+    /// # Example
+    /// ```
+    /// use mu_db::Health;
+    /// let mut db = mu_db::DataBase::new("./test_health.db").unwrap();
+    /// db.clear_all().unwrap();
+    /// db.insert("k1", "hello");
+    /// db.insert("k2", "world");
+    /// assert_eq!(db.health(), Health::Ok);
+    /// db.remove("k1").unwrap();
+    /// assert_eq!(db.health(), Health::FragmentedShouldShrink { reclaimable: 5 });
+    /// db.shrink();
+    /// assert_eq!(db.health(), Health::Ok);
+    /// ```
+    pub fn health(&self) -> Health {
+        let issues = self.verify();
+        if !issues.is_empty() {
+            return Health::Corrupt { issues };
+        }
+        let reclaimable = self.reclaimable_bytes();
+        let buf_len = self.buf_len();
+        if reclaimable > 0 && reclaimable as f64 >= buf_len as f64 * SHRINK_RECOMMENDED_RATIO {
+            Health::FragmentedShouldShrink { reclaimable }
+        } else {
+            Health::Ok
+        }
+    }
+    /// Groups keys into runs of physically contiguous entries, in file offset order.
+    ///
+    /// Every key belongs to exactly one run, a run of one key has a gap on both sides.
+    /// # Example
+    /// ```
+    /// let mut db = mu_db::DataBase::new("./test_adjacent_runs.db").unwrap();
+    /// db.clear_all().unwrap();
+    /// db.insert("a", "1");
+    /// db.insert("b", "2");
+    /// db.insert("c", "3");
+    /// db.insert("d", "4");
+    /// db.remove("c").unwrap();
+    /// assert_eq!(db.adjacent_runs(), vec![vec!["a", "b"], vec!["d"]]);
+    /// ```
+    pub fn adjacent_runs(&self) -> Vec<Vec<String>> {
+        let mut runs: Vec<Vec<String>> = Vec::new();
+        let mut prev_end = None;
+        for e in self.index.entries.iter() {
+            match runs.last_mut() {
+                Some(run) if prev_end == Some(e.range.start) => run.push(e.key.clone()),
+                _ => runs.push(vec![e.key.clone()]),
             }
+            prev_end = Some(e.range.end);
         }
-        // else if entry doesnt fit:
-        let range_start = if let Some(e) = self.entries.last() {
-            e.range.end
+        runs
+    }
+}
+
+/// Best effort (`.flush()`), errors are ignored. Call it (or (`.flush_all()`)) before dropping
+/// to handle them.
+impl Drop for DataBase {
+    fn drop(&mut self) {
+        let _ = self.flush();
+    }
+}
+
+impl Index {
+    /// Opens (or creates) the index file at `path`.
+    pub fn new(path: &str) -> Result<Self> {
+        Index::open(path, 0)
+    }
+    fn open(path: &str, capacity: usize) -> Result<Self> {
+        let mut index_file = open_file(path)?;
+        let mut content = Vec::new();
+        index_file.read_to_end(&mut content)?;
+        let header = content
+            .get(..8)
+            .and_then(|h| footer_version(h, BINARY_INDEX_MAGIC));
+        let binary = match header {
+            Some(found) if found != FORMAT_VERSION => {
+                return Err(VersionMismatch {
+                    path: Some(PathBuf::from(path)),
+                    found,
+                }
+                .into())
+            }
+            Some(_) => true,
+            None => false,
+        };
+        let mut entries = if binary {
+            Index::parse_index_binary_with_capacity(&content, capacity)
         } else {
-            0
+            let content = String::from_utf8(content)
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+            Index::parse_index_with_capacity(content, capacity)
+        }
+        .map_err(|e| e.with_path(path))?;
+        // written sorted by key, see [Options::max_index_entries]:
+        if !entries.is_sorted_by_key(|e| e.range.start) {
+            entries.sort_by_key(|e| e.range.start);
+        }
+        let mut index = Index::with_entries(entries, index_file, path);
+        index.binary = binary;
+        Ok(index)
+    }
+    /// Returns an index of `entries` read from the index file `file` at `path`.
+    fn with_entries(entries: Vec<IndexEntry>, file: File, path: &str) -> Self {
+        let mut index = Index {
+            entries,
+            tags: HashMap::new(),
+            key_order: None,
+            key_hashes: None,
+            shrink_tail: ShrinkTailPolicy::default(),
+            io_retries: 0,
+            embedded_at: None,
+            coalesce: None,
+            reserved_prefix: 0,
+            free: None,
+            pending_writes: 0,
+            last_write: Instant::now(),
+            writes: 0,
+            background: None,
+            binary: false,
+            checksums: false,
+            offload: None,
+            writer: Arc::new(Mutex::new(BufWriter::new(file))),
+            path: PathBuf::from(path),
         };
-        let entry = IndexEntry {
-            key: key.to_string(),
-            range: range_start..range_start + entry_size,
+        index.rebuild_tags();
+        index
+    }
+    /// Opens the index stored at the end of the db file at `path`, see [Options::single_file].
+    fn open_embedded(path: &str, capacity: usize) -> Result<Self> {
+        let mut file = open_file(path)?;
+        let len = file.metadata()?.len();
+        let mut entries = Vec::new();
+        let mut embedded_at = len;
+        if len >= 16 {
+            let mut footer = [0; 16];
+            file.seek(SeekFrom::Start(len - 16))?;
+            file.read_exact(&mut footer)?;
+            let index_len = u64::from_le_bytes(footer[..8].try_into().unwrap());
+            if let Some(found) = footer_version(&footer[8..], EMBEDDED_INDEX_MAGIC) {
+                if found != FORMAT_VERSION {
+                    return Err(VersionMismatch {
+                        path: Some(PathBuf::from(path)),
+                        found,
+                    }
+                    .into());
+                }
+            }
+            if &footer[8..] == EMBEDDED_INDEX_MAGIC && index_len <= len - 16 {
+                embedded_at = len - 16 - index_len;
+                let mut index_string = String::new();
+                file.seek(SeekFrom::Start(embedded_at))?;
+                (&mut file)
+                    .take(index_len)
+                    .read_to_string(&mut index_string)?;
+                entries = Index::parse_index_with_capacity(index_string, capacity)
+                    .map_err(|e| e.with_path(path))?;
+            }
+        }
+        let mut index = Index::with_entries(entries, file, path);
+        index.embedded_at = Some(embedded_at);
+        Ok(index)
+    }
+    /// Opens the index file at `path` keeping about `max_entries` entries in memory, see
+    /// [Options::max_index_entries]. An index file that isn't sorted by key is loaded once
+    /// and written sorted.
+    fn open_offloaded(path: &str, max_entries: usize) -> Result<Self> {
+        let file = open_file(path)?;
+        let reader = BufReader::new(file.try_clone()?);
+        let mut index = Index::with_entries(Vec::new(), file, path);
+        index.offload = Some(Offload {
+            max_entries,
+            reader: Arc::new(Mutex::new(reader)),
+            fences: Vec::new(),
+            count: 0,
+            end: 0,
+            removed: HashSet::new(),
+            recent: VecDeque::new(),
+            dirty: false,
+            complete: false,
+        });
+        if !index.scan_offloaded()? {
+            index.entries = Index::open(path, 0)?.entries;
+            index.rebuild_tags();
+            index.offload.as_mut().unwrap().complete = true;
+            index.try_write_index()?;
+        }
+        Ok(index)
+    }
+    /// Reads the fences, number of entries and end of the index file, returns `false` if it
+    /// isn't a text index sorted by key.
+    fn scan_offloaded(&mut self) -> Result<bool> {
+        let o = self.offload.as_mut().unwrap();
+        let mut r = o.reader.lock().unwrap();
+        r.seek(SeekFrom::Start(0))?;
+        let (mut fences, mut count, mut end, mut offset) = (Vec::new(), 0, 0, 0);
+        let mut prev: Option<String> = None;
+        let mut line = Vec::new();
+        loop {
+            line.clear();
+            let n = r.read_until(b'\n', &mut line)?;
+            if n == 0 {
+                break;
+            }
+            if count == 0 && line.starts_with(&BINARY_INDEX_MAGIC[..7]) {
+                return Ok(false);
+            }
+            let entry = Index::parse_line(&line, count + 1).map_err(|e| e.with_path(&self.path))?;
+            if prev.as_ref().is_some_and(|p| *p >= entry.key) {
+                return Ok(false);
+            }
+            if count % OFFLOAD_FENCE_STRIDE == 0 {
+                fences.push((entry.key.clone(), offset));
+            }
+            end = end.max(entry.occupied_end());
+            offset += n as u64;
+            count += 1;
+            prev = Some(entry.key);
+        }
+        drop(r);
+        o.fences = fences;
+        o.count = count;
+        o.end = end;
+        Ok(true)
+    }
+    /// Parses line `n` (1-based) of a text index file, with or without its newline.
+    fn parse_line(line: &[u8], n: usize) -> std::result::Result<IndexEntry, IndexParseError> {
+        let err = |e: IndexParseError| IndexParseError { line: n, ..e };
+        let line = line.strip_suffix(b"\n").unwrap_or(line);
+        let Ok(text) = std::str::from_utf8(line) else {
+            return Err(IndexParseError {
+                path: None,
+                line: n,
+                content: String::from_utf8_lossy(line).into(),
+                reason: "line is not UTF-8".to_string(),
+            });
+        };
+        let mut entries = Index::parse_index(format!("{}\n", text)).map_err(err)?;
+        Ok(entries.remove(0))
+    }
+    /// Reads the entry of `key` from the index file, if it's there and wasn't removed since
+    /// the last index write. Doesn't look at the entries in memory, see
+    /// [Options::max_index_entries].
+    fn cold_entry(&self, key: &str) -> Result<Option<IndexEntry>> {
+        let Some(o) = &self.offload else {
+            return Ok(None);
+        };
+        if o.complete || o.removed.contains(key) {
+            return Ok(None);
+        }
+        let fence = o.fences.partition_point(|(k, _)| k.as_str() <= key);
+        if fence == 0 {
+            return Ok(None);
+        }
+        let mut r = o.reader.lock().unwrap();
+        r.seek(SeekFrom::Start(o.fences[fence - 1].1))?;
+        let mut line = Vec::new();
+        for n in 0..OFFLOAD_FENCE_STRIDE {
+            line.clear();
+            if r.read_until(b'\n', &mut line)? == 0 {
+                break;
+            }
+            let entry = Index::parse_line(&line, (fence - 1) * OFFLOAD_FENCE_STRIDE + n + 1)
+                .map_err(|e| e.with_path(&self.path))?;
+            match entry.key.as_str().cmp(key) {
+                std::cmp::Ordering::Less => {}
+                std::cmp::Ordering::Equal => return Ok(Some(entry)),
+                std::cmp::Ordering::Greater => break,
+            }
+        }
+        Ok(None)
+    }
+    /// Moves the entry of `key` into memory if it's only in the index file, after dropping
+    /// the entries not used recently if there are too many, see [Options::max_index_entries].
+    fn promote(&mut self, key: &str) -> Result<()> {
+        let Some(o) = self.offload.as_mut() else {
+            return Ok(());
+        };
+        o.recent.push_back(key.to_string());
+        if o.recent.len() > o.max_entries / 2 {
+            o.recent.pop_front();
+        }
+        self.evict();
+        if self.position(key).is_some() {
+            return Ok(());
+        }
+        let Some(entry) = self.cold_entry(key)? else {
+            return Ok(());
+        };
+        let pos = self
+            .entries
+            .partition_point(|e| e.range.start <= entry.range.start);
+        let hash = self.hash_of(key);
+        let dirty = self.offload.as_ref().unwrap().dirty;
+        self.insert_at(pos, entry, hash);
+        self.tag_all(pos);
+        // the entry is unchanged, it's still written:
+        let o = self.offload.as_mut().unwrap();
+        o.dirty = dirty;
+        o.count -= 1;
+        Ok(())
+    }
+    /// Drops the entries not used recently from memory if there are more than `max_entries`
+    /// and all of them are written, see [Options::max_index_entries].
+    fn evict(&mut self) {
+        let Some(o) = self.offload.as_mut() else {
+            return;
+        };
+        if self.entries.len() <= o.max_entries || o.dirty || self.pending_writes > 0 {
+            return;
+        }
+        let before = self.entries.len();
+        let keep: HashSet<&str> = o.recent.iter().map(String::as_str).collect();
+        self.entries.retain(|e| keep.contains(e.key.as_str()));
+        o.count += before - self.entries.len();
+        o.complete = false;
+        self.free = None;
+        self.rebuild_tags();
+        self.rebuild_key_order();
+    }
+    /// Reads every entry that is only in the index file into memory, see
+    /// [Options::max_index_entries]. They stay until entries are dropped again by the next
+    /// lookup of a key after an index write.
+    pub fn load_all(&mut self) -> Result<()> {
+        let Some(o) = self.offload.as_mut() else {
+            return Ok(());
+        };
+        if o.complete {
+            return Ok(());
+        }
+        let mut content = String::new();
+        {
+            let mut r = o.reader.lock().unwrap();
+            r.seek(SeekFrom::Start(0))?;
+            r.read_to_string(&mut content)?;
+        }
+        let mut cold = Index::parse_index(content).map_err(|e| e.with_path(&self.path))?;
+        let hot: HashSet<&str> = self.entries.iter().map(|e| e.key.as_str()).collect();
+        cold.retain(|e| !hot.contains(e.key.as_str()) && !o.removed.contains(&e.key));
+        self.entries.extend(cold);
+        self.entries.sort_by_key(|e| e.range.start);
+        o.count = 0;
+        o.complete = true;
+        self.free = None;
+        self.rebuild_tags();
+        self.rebuild_key_order();
+        Ok(())
+    }
+    /// Returns `true` if `key` has an entry, in memory or in the index file.
+    pub fn contains(&self, key: &str) -> bool {
+        self.position(key).is_some() || self.cold_entry(key).unwrap().is_some()
+    }
+    /// Writes the index file sorted by key, merging the entries in memory with the ones only
+    /// in the current file, see [Options::max_index_entries].
+    fn write_offloaded(&mut self) -> Result<()> {
+        let mut hot: Vec<&IndexEntry> = self.entries.iter().collect();
+        hot.sort_by(|a, b| a.key.cmp(&b.key));
+        let o = self.offload.as_mut().unwrap();
+        let path = &self.path;
+        let checksums = self.checksums;
+        let mut tmp = path.as_os_str().to_os_string();
+        tmp.push(".tmp");
+        let tmp = PathBuf::from(tmp);
+        let written = retry_transient(self.io_retries, || {
+            let mut r = o.reader.lock().unwrap();
+            r.seek(SeekFrom::Start(0))?;
+            let mut w = BufWriter::new(File::create(&tmp)?);
+            let (mut fences, mut lines, mut cold, mut end, mut offset) = (Vec::new(), 0, 0, 0, 0);
+            let mut last: Option<String> = None;
+            let mut hot = hot.iter().peekable();
+            let mut old: Option<(IndexEntry, Vec<u8>)> = None;
+            let mut old_lines = if o.complete { None } else { Some(0) };
+            loop {
+                while let (None, Some(n)) = (&old, old_lines.as_mut()) {
+                    let mut line = Vec::new();
+                    if r.read_until(b'\n', &mut line)? == 0 {
+                        old_lines = None;
+                        break;
+                    }
+                    *n += 1;
+                    let entry = Index::parse_line(&line, *n).map_err(|e| e.with_path(path))?;
+                    if !o.removed.contains(&entry.key) {
+                        if !line.ends_with(b"\n") {
+                            line.push(b'\n');
+                        }
+                        old = Some((entry, line));
+                    }
+                }
+                // an entry in memory replaces the line of its key:
+                let (entry, line, in_memory) = match (hot.peek(), old.take()) {
+                    (None, None) => break,
+                    (Some(h), Some((e, line))) if e.key < h.key => (e, line, false),
+                    (None, Some((e, line))) => (e, line, false),
+                    (Some(_), same) => {
+                        let h = *hot.next().unwrap();
+                        old = same.filter(|(e, _)| e.key != h.key);
+                        let mut line = Index::entry_line(h);
+                        if checksums {
+                            let plain = line.clone();
+                            push_checksum(&mut line, &plain);
+                        }
+                        line.push('\n');
+                        (h.clone(), line.into_bytes(), true)
+                    }
+                };
+                if last.as_ref() == Some(&entry.key) {
+                    continue;
+                }
+                if lines % OFFLOAD_FENCE_STRIDE == 0 {
+                    fences.push((entry.key.clone(), offset));
+                }
+                w.write_all(&line)?;
+                offset += line.len() as u64;
+                lines += 1;
+                if !in_memory {
+                    cold += 1;
+                }
+                end = end.max(entry.occupied_end());
+                last = Some(entry.key);
+            }
+            let file = w.into_inner().map_err(|e| e.into_error())?;
+            file.sync_all()?;
+            std::fs::rename(&tmp, path)?;
+            #[cfg(unix)]
+            File::open(parent_dir(path))?.sync_all()?;
+            Ok((file, fences, cold, end))
+        });
+        let (file, fences, cold, end) = match written {
+            Ok(written) => written,
+            Err(e) => {
+                let _ = std::fs::remove_file(&tmp);
+                return Err(e);
+            }
         };
-        self.entries.push(entry.clone());
+        // the old handles still point at the replaced file:
+        *self.writer.lock().unwrap() = BufWriter::new(file);
+        o.reader = Arc::new(Mutex::new(BufReader::new(File::open(path)?)));
+        o.fences = fences;
+        o.count = cold;
+        o.end = end;
+        o.removed.clear();
+        o.complete = false;
+        Ok(())
+    }
+    /// Switches the index to the file at `path`, which must hold the same index (e.g. after
+    /// renaming the index file).
+    fn reopen(&mut self, path: &str) -> Result<()> {
+        self.wait_background()?;
+        self.writer = Arc::new(Mutex::new(BufWriter::new(open_file(path)?)));
+        if let Some(o) = self.offload.as_mut() {
+            o.reader = Arc::new(Mutex::new(BufReader::new(File::open(path)?)));
+        }
+        self.path = PathBuf::from(path);
+        self.set_background_writes(self.background.is_some())
+    }
+    /// Returns the length of the values in the db file if the index is stored after them.
+    pub fn embedded_len(&self) -> Option<u64> {
+        self.embedded_at
+    }
+    /// Moves the embedded index block to `len` (or after the last entry if that's further)
+    /// and writes it, see [Options::single_file]. Does nothing for a separate index file.
+    pub fn set_embedded_len(&mut self, len: u64) -> Result<()> {
+        if self.embedded_at.is_none() {
+            return Ok(());
+        }
+        self.embedded_at = Some(len);
+        self.try_write_index()
+    }
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+    /// Flushes the index file and waits until it's on disk.
+    pub fn sync(&mut self) -> Result<()> {
+        self.flush()?;
+        let mut w = self.writer.lock().unwrap();
+        w.flush()?;
+        w.get_ref().sync_all()
+    }
+    /// Switches how keys are looked up, see [KeyLookup].
+    pub fn set_key_lookup(&mut self, lookup: KeyLookup) {
+        self.key_order = None;
+        self.key_hashes = None;
+        match lookup {
+            KeyLookup::Linear => {}
+            // as much room as the entries, see (`DataBaseBuilder::index_capacity()`):
+            KeyLookup::BinarySearch => {
+                self.key_order = Some(Vec::with_capacity(self.entries.capacity()))
+            }
+            KeyLookup::Hash => {
+                self.key_hashes = Some(HashMap::with_capacity_and_hasher(
+                    self.entries.capacity(),
+                    Default::default(),
+                ))
+            }
+        }
+        self.rebuild_key_order();
+    }
+    /// Sets what happens to the freed tail of a shrinking overwrite, see [ShrinkTailPolicy].
+    pub fn set_shrink_tail_policy(&mut self, policy: ShrinkTailPolicy) {
+        self.shrink_tail = policy;
+    }
+    /// Sets how long index writes are deferred, see [IndexWriteCoalescing].
+    pub fn set_coalescing(&mut self, coalesce: Option<IndexWriteCoalescing>) {
+        self.coalesce = coalesce;
+    }
+    /// Returns how many times the index was written since it was opened.
+    pub fn writes(&self) -> u64 {
+        self.writes
+    }
+    /// Writes the index if mutations are pending.
+    /// Also waits for the writes queued for the index writer thread, see
+    /// [Options::background_index_writes].
+    pub fn flush(&mut self) -> Result<()> {
+        if self.pending_writes > 0 {
+            self.try_write_index()?;
+        }
+        self.wait_background()
+    }
+    /// Keeps new and compacted entries out of bytes `0..n` of the db file.
+    pub fn set_reserved_prefix(&mut self, n: usize) {
+        self.reserved_prefix = n;
+        self.free = None;
+    }
+    /// Returns where the space after the last entry (or the reserved prefix) starts.
+    fn data_end(&self) -> usize {
+        let end = self
+            .entries
+            .last()
+            .map_or(self.reserved_prefix, |e| e.occupied_end());
+        self.offload.as_ref().map_or(end, |o| end.max(o.end))
+    }
+    /// Sets how many times index writes are retried on transient errors.
+    pub fn set_io_retries(&mut self, retries: usize) {
+        self.io_retries = retries;
+    }
+    pub fn key_lookup(&self) -> KeyLookup {
+        if self.key_order.is_some() {
+            KeyLookup::BinarySearch
+        } else if self.key_hashes.is_some() {
+            KeyLookup::Hash
+        } else {
+            KeyLookup::Linear
+        }
+    }
+    pub fn len(&self) -> usize {
+        self.entries.len() + self.offload.as_ref().map_or(0, |o| o.count)
+    }
+    /// Returns all keys in file offset order.
+    pub fn keys(&self) -> impl Iterator<Item = &str> {
+        self.entries.iter().map(|e| e.key.as_str())
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    pub fn insert_entry(&mut self, entry_size: usize, key: &str) -> IndexEntry {
+        let entry = self.place_entry(entry_size, key);
+        self.write_index();
+        entry
+    }
+    /// Same as `insert_entry` for a value stored in the entry itself, the entry gets an empty
+    /// range and its old range (if any) is freed.
+    pub fn insert_inline_entry(&mut self, key: &str, value: &str) -> IndexEntry {
+        let entry = self.place_inline_entry(key, value);
         self.write_index();
-        return entry;
+        entry
+    }
+    /// Same as `insert_inline_entry` without writing the index file.
+    fn place_inline_entry(&mut self, key: &str, value: &str) -> IndexEntry {
+        self.promote(key).unwrap();
+        let hash = self.hash_of(key);
+        let pos = self.place_entry_found(self.position_hashed(key, hash), 0, key, hash);
+        let entry = &mut self.entries[pos];
+        entry.inline = Some(value.to_string());
+        entry.clone()
+    }
+    pub fn alloc_entry(&mut self, entry_size: usize, key: &str) -> IndexEntry {
+        let entry = self.place_new_entry(entry_size, key);
+        self.write_index();
+        entry
+    }
+    /// Same as `insert_entry` with the hash of `key` already computed by
+    /// [DataBase::hash_key], which [KeyLookup::Hash] uses instead of hashing it again. A wrong
+    /// hash corrupts lookups of `key`, it's only checked in debug builds.
+    pub fn insert_entry_prehashed(
+        &mut self,
+        entry_size: usize,
+        key: &str,
+        hash: u64,
+    ) -> IndexEntry {
+        debug_assert_eq!(hash, DataBase::hash_key(key), "hash of {:?}", key);
+        let hash = self.key_hashes.as_ref().map(|_| hash);
+        let entry = self.place_entry_hashed(entry_size, key, hash);
+        self.write_index();
+        entry
+    }
+    /// Same as `insert_entry` without writing the index file.
+    fn place_entry(&mut self, entry_size: usize, key: &str) -> IndexEntry {
+        self.place_entry_hashed(entry_size, key, self.hash_of(key))
+    }
+    fn place_entry_hashed(
+        &mut self,
+        entry_size: usize,
+        key: &str,
+        hash: Option<u64>,
+    ) -> IndexEntry {
+        self.promote(key).unwrap();
+        let i = self.place_entry_found(self.position_hashed(key, hash), entry_size, key, hash);
+        self.entries[i].clone()
+    }
+    /// Same as `place_entry_hashed` with `pos` being `position_hashed(key, hash)`, already
+    /// looked up by the caller. Returns the new position of the entry.
+    fn place_entry_found(
+        &mut self,
+        pos: Option<usize>,
+        entry_size: usize,
+        key: &str,
+        hash: Option<u64>,
+    ) -> usize {
+        match pos {
+            Some(pos) => {
+                let old = &self.entries[pos];
+                if old.capacity() < entry_size {
+                    let old = self.remove_at(pos);
+                    let i = self.place_new_entry_at(entry_size, key, hash);
+                    self.entries[i].tags = old.tags;
+                    self.entries[i].version = old.version + 1;
+                    self.entries[i].content_type = old.content_type;
+                    self.tag_all(i);
+                    i
+                } else {
+                    let keep_tail = self.shrink_tail == ShrinkTailPolicy::Keep;
+                    if let Some(o) = self.offload.as_mut() {
+                        o.dirty = true;
+                    }
+                    let entry = &mut self.entries[pos];
+                    let capacity = entry.capacity();
+                    entry.range = entry.range.start..entry.range.start + entry_size;
+                    entry.reserved = if keep_tail { capacity - entry_size } else { 0 };
+                    entry.version += 1;
+                    entry.inline = None;
+                    self.refresh_gap_after(pos);
+                    pos
+                }
+            }
+            None => self.place_new_entry_at(entry_size, key, hash),
+        }
+    }
+    /// Places the entry of `key` at `start`, which must be at or after `data_end()`, keeping
+    /// the metadata of an existing entry. Doesn't write the index file.
+    fn append_entry(&mut self, entry_size: usize, key: &str, start: usize) -> IndexEntry {
+        self.promote(key).unwrap();
+        let hash = self.hash_of(key);
+        let mut entry = IndexEntry::new(key.to_string(), start..start + entry_size);
+        if let Some(pos) = self.position_hashed(key, hash) {
+            let old = self.remove_at(pos);
+            entry.tags = old.tags;
+            entry.version = old.version + 1;
+            entry.content_type = old.content_type;
+        }
+        let pos = self.entries.len();
+        self.insert_at(pos, entry, hash);
+        self.tag_all(pos);
+        self.entries[pos].clone()
+    }
+    /// Returns the entries and the state of offloaded ones, for `restore`.
+    fn checkpoint(&self) -> (Vec<IndexEntry>, Option<Offload>) {
+        (self.entries.clone(), self.offload.clone())
+    }
+    /// Goes back to a `checkpoint` without writing the index file, e.g. to undo changes that
+    /// weren't written yet.
+    fn restore(&mut self, (entries, offload): (Vec<IndexEntry>, Option<Offload>)) {
+        self.entries = entries;
+        self.offload = offload;
+        self.free = None;
+        self.rebuild_tags();
+        self.rebuild_key_order();
+    }
+    /// Same as `alloc_entry` without writing the index file.
+    fn place_new_entry(&mut self, entry_size: usize, key: &str) -> IndexEntry {
+        let i = self.place_new_entry_at(entry_size, key, self.hash_of(key));
+        self.entries[i].clone()
+    }
+    /// Allocates an untagged entry and returns its position in `self.entries`.
+    ///
+    /// The entry goes in the first free range (in offset order) it fits in, found in the free
+    /// list, or after the last entry.
+    fn place_new_entry_at(&mut self, entry_size: usize, key: &str, hash: Option<u64>) -> usize {
+        let prefix = self.reserved_prefix;
+        if entry_size == 0
+            && self
+                .entries
+                .first()
+                .is_some_and(|e| e.range.start >= prefix)
+        {
+            let entry = IndexEntry::new(key.to_string(), prefix..prefix);
+            self.insert_at(0, entry, hash);
+            return 0;
+        }
+        // entries only in the index file may lie between the ones in memory, see
+        // [Options::max_index_entries]:
+        if self.free.is_none() && self.offload.is_none() {
+            self.free = Some(self.gaps());
+        }
+        let fit = self
+            .free
+            .as_ref()
+            .and_then(|free| free.iter().find(|(_, &len)| len >= entry_size));
+        if let Some((&start, _)) = fit {
+            let pos = self.entries.partition_point(|e| e.range.start <= start);
+            let entry = IndexEntry::new(key.to_string(), start..start + entry_size);
+            self.insert_at(pos, entry, hash);
+            return pos;
+        }
+        // else if entry doesnt fit:
+        let range_start = self.data_end();
+        let pos = self.entries.len();
+        self.insert_at(
+            pos,
+            IndexEntry::new(key.to_string(), range_start..range_start + entry_size),
+            hash,
+        );
+        pos
     }
     pub fn remove_entry(&mut self, key: &str) -> Option<IndexEntry> {
-        for (i, entry) in self.entries.iter().enumerate() {
-            if entry.key == key {
-                let removed = self.entries.remove(i);
-                self.write_index();
-                return Some(removed);
+        self.promote(key).unwrap();
+        let removed = self.remove_at(self.position(key)?);
+        self.write_index();
+        Some(removed)
+    }
+    /// Removes every entry matching `f` with a single index write, returns their keys in
+    /// offset order.
+    pub fn remove_entries_where(&mut self, mut f: impl FnMut(&IndexEntry) -> bool) -> Vec<String> {
+        self.load_all().unwrap();
+        let mut removed = Vec::new();
+        let mut i = 0;
+        while i < self.entries.len() {
+            if f(&self.entries[i]) {
+                removed.push(self.remove_at(i).key);
+            } else {
+                i += 1;
+            }
+        }
+        if !removed.is_empty() {
+            self.write_index();
+        }
+        removed
+    }
+    pub fn get_entry(&self, key: &str) -> Option<IndexEntry> {
+        match self.position(key) {
+            Some(i) => Some(self.entries[i].clone()),
+            None => self.cold_entry(key).unwrap(),
+        }
+    }
+    /// Returns the key and range of the entry whose range contains `offset`, see
+    /// [DataBase::entry_at_offset].
+    pub fn entry_at_offset(&self, offset: usize) -> Option<(String, Range<usize>)> {
+        let end = self.entries.partition_point(|e| e.range.start <= offset);
+        self.entries[..end]
+            .iter()
+            .rev()
+            .find(|e| e.size() != 0)
+            .filter(|e| e.range.contains(&offset))
+            .map(|e| (e.key.clone(), e.range.clone()))
+    }
+    /// Same as `get_entry` but only returns the range of the entry, see [Head].
+    pub fn head(&self, key: &str) -> Option<Head> {
+        let e = match self.position(key) {
+            Some(i) => Cow::Borrowed(&self.entries[i]),
+            None => Cow::Owned(self.cold_entry(key).unwrap()?),
+        };
+        Some(Head {
+            start: e.range.start,
+            size: e.inline.as_ref().map_or(e.size(), |v| v.len()),
+        })
+    }
+    /// Returns the hash of `key` if [KeyLookup::Hash] needs it.
+    fn hash_of(&self, key: &str) -> Option<u64> {
+        self.key_hashes.as_ref().map(|_| DataBase::hash_key(key))
+    }
+    /// Returns the position of `key` in `self.entries`.
+    fn position(&self, key: &str) -> Option<usize> {
+        self.position_hashed(key, self.hash_of(key))
+    }
+    /// Same as `position` with `hash` being `hash_of(key)`.
+    fn position_hashed(&self, key: &str, hash: Option<u64>) -> Option<usize> {
+        if let (Some(hashes), Some(hash)) = (&self.key_hashes, hash) {
+            return hashes
+                .get(&hash)?
+                .iter()
+                .copied()
+                .find(|&i| self.entries[i].key == key);
+        }
+        match &self.key_order {
+            Some(order) => order
+                .binary_search_by(|&i| self.entries[i].key.as_str().cmp(key))
+                .ok()
+                .map(|p| order[p]),
+            None => self.entries.iter().position(|i| i.key == key),
+        }
+    }
+    /// Returns the free ranges between entries, see `free`.
+    fn gaps(&self) -> BTreeMap<usize, usize> {
+        let mut free = BTreeMap::new();
+        let mut end = self.reserved_prefix;
+        for e in self.entries.iter() {
+            if e.range.start > end {
+                free.insert(end, e.range.start - end);
+            }
+            end = e.occupied_end();
+        }
+        free
+    }
+    /// Updates the free range after the entry at `pos`, whose size changed.
+    fn refresh_gap_after(&mut self, pos: usize) {
+        let Some(free) = self.free.as_mut() else {
+            return;
+        };
+        let e = &self.entries[pos];
+        let end = e.occupied_end();
+        match self.entries.get(pos + 1) {
+            Some(next) => {
+                let stale: Vec<usize> = free
+                    .range(e.range.start..next.range.start)
+                    .map(|(&start, _)| start)
+                    .collect();
+                for start in stale {
+                    free.remove(&start);
+                }
+                if next.range.start > end {
+                    free.insert(end, next.range.start - end);
+                }
+            }
+            None => free.retain(|&start, _| start < e.range.start),
+        }
+    }
+    /// Inserts `entry` at `pos` in `self.entries`, keeping the lookup structures in sync.
+    /// `hash` is `hash_of(&entry.key)`.
+    fn insert_at(&mut self, pos: usize, entry: IndexEntry, hash: Option<u64>) {
+        if let Some(free) = self.free.as_mut() {
+            let (start, end) = (entry.range.start, entry.occupied_end());
+            let gap = free
+                .range(..=start)
+                .next_back()
+                .map(|(&s, &len)| (s, s + len));
+            match gap {
+                // the entry takes (part of) a free range:
+                Some((gap_start, gap_end)) if start < gap_end && end > start => {
+                    free.remove(&gap_start);
+                    if start > gap_start {
+                        free.insert(gap_start, start - gap_start);
+                    }
+                    if gap_end > end {
+                        free.insert(end, gap_end - end);
+                    }
+                }
+                // appended past the end of the last entry:
+                _ if pos == self.entries.len() => {
+                    let prev_end = self
+                        .entries
+                        .last()
+                        .map_or(self.reserved_prefix, |e| e.occupied_end());
+                    if start > prev_end {
+                        free.insert(prev_end, start - prev_end);
+                    }
+                }
+                _ => {}
+            }
+        }
+        if let Some(o) = self.offload.as_mut() {
+            o.dirty = true;
+        }
+        // appending doesn't shift any position:
+        let shift = pos < self.entries.len();
+        if let (Some(hashes), Some(hash)) = (self.key_hashes.as_mut(), hash) {
+            if shift {
+                for i in hashes.values_mut().flatten() {
+                    if *i >= pos {
+                        *i += 1;
+                    }
+                }
+            }
+            hashes.entry(hash).or_default().push(pos);
+        }
+        if let Some(order) = self.key_order.as_mut() {
+            for i in order.iter_mut() {
+                if *i >= pos {
+                    *i += 1;
+                }
+            }
+            let p = order
+                .binary_search_by(|&i| {
+                    self.entries[if i > pos { i - 1 } else { i }]
+                        .key
+                        .cmp(&entry.key)
+                })
+                .unwrap_or_else(|p| p);
+            order.insert(p, pos);
+        }
+        self.entries.insert(pos, entry);
+    }
+    /// Removes the entry at `pos` in `self.entries`, keeping the lookup structures in sync.
+    fn remove_at(&mut self, pos: usize) -> IndexEntry {
+        let removed = self.entries.remove(pos);
+        if let Some(o) = self.offload.as_mut() {
+            o.dirty = true;
+            o.removed.insert(removed.key.clone());
+        }
+        let shift = pos < self.entries.len();
+        if let Some(free) = self.free.as_mut() {
+            let prev_end = match pos {
+                0 => self.reserved_prefix,
+                _ => self.entries[pos - 1].occupied_end(),
+            };
+            // the free ranges before and after the removed entry merge:
+            if removed.range.start > prev_end {
+                free.remove(&prev_end);
+            }
+            if let Some(next) = self.entries.get(pos) {
+                if next.range.start > removed.occupied_end() {
+                    free.remove(&removed.occupied_end());
+                }
+                if next.range.start > prev_end {
+                    free.insert(prev_end, next.range.start - prev_end);
+                }
+            }
+        }
+        if let Some(hashes) = self.key_hashes.as_mut() {
+            let hash = DataBase::hash_key(&removed.key);
+            if let Some(bucket) = hashes.get_mut(&hash) {
+                bucket.retain(|&i| i != pos);
+                if bucket.is_empty() {
+                    hashes.remove(&hash);
+                }
+            }
+            if shift {
+                for i in hashes.values_mut().flatten() {
+                    if *i > pos {
+                        *i -= 1;
+                    }
+                }
+            }
+        }
+        if let Some(order) = self.key_order.as_mut() {
+            order.retain(|&i| i != pos);
+            for i in order.iter_mut() {
+                if *i > pos {
+                    *i -= 1;
+                }
+            }
+        }
+        self.untag_all(&removed);
+        removed
+    }
+    /// Writes the index file, or only counts the mutation as pending while it's deferred,
+    /// see [IndexWriteCoalescing].
+    pub fn write_index(&mut self) {
+        if let Some(c) = self.coalesce {
+            self.pending_writes += 1;
+            let due = self.last_write.elapsed() >= c.max_delay;
+            if !due && self.pending_writes < c.max_mutations {
+                return;
             }
         }
-        None
+        self.try_write_index().unwrap();
+    }
+    /// Writes the index file now, returning I/O errors instead of panicking.
+    ///
+    /// The new index goes to a temporary file next to the index file and is renamed over it,
+    /// so a crash mid-write leaves the previous index intact.
+    /// # Example
+    /// ```
+    /// let mut db = mu_db::DataBase::new("./test_try_write_index.db").unwrap();
+    /// db.clear_all().unwrap();
+    /// db.insert("key", "value");
+    /// let index = std::fs::read_to_string("./index_test_try_write_index.db").unwrap();
+    /// assert_eq!(index, "key=0_5=version:1\n");
+    /// assert!(!std::path::Path::new("./index_test_try_write_index.db.tmp").exists());
+    /// ```
+    /// A write interrupted before the rename only leaves a partial temporary file, which is
+    /// ignored when opening and replaced by the next write:
+    /// ```
+    /// let mut db = mu_db::DataBase::new("./test_partial_index_write.db").unwrap();
+    /// db.clear_all().unwrap();
+    /// db.insert("key", "value");
+    /// drop(db);
+    /// std::fs::write("./index_test_partial_index_write.db.tmp", "key=0_5=ver").unwrap();
+    ///
+    /// let mut db = mu_db::DataBase::new("./test_partial_index_write.db").unwrap();
+    /// assert_eq!(db.get("key"), Some("value".to_string()));
+    /// db.insert("other", "value");
+    /// assert!(!std::path::Path::new("./index_test_partial_index_write.db.tmp").exists());
+    /// ```
+    pub fn try_write_index(&mut self) -> Result<()> {
+        self.write_index_now()?;
+        if let Some(o) = self.offload.as_mut() {
+            o.dirty = false;
+        }
+        self.pending_writes = 0;
+        self.last_write = Instant::now();
+        self.writes += 1;
+        Ok(())
+    }
+    fn write_index_now(&mut self) -> Result<()> {
+        if self.offload.is_some() {
+            return self.write_offloaded();
+        }
+        let content = self.file_bytes();
+        if let Some(at) = self.embedded_at {
+            let end = self.data_end() as u64;
+            let at = at.max(end);
+            self.embedded_at = Some(at);
+            let mut block = content;
+            block.extend_from_slice(&(block.len() as u64).to_le_bytes());
+            block.extend_from_slice(EMBEDDED_INDEX_MAGIC);
+            let mut binding = self.writer.lock().unwrap();
+            let w = binding.get_mut();
+            return retry_transient(self.io_retries, || {
+                w.seek(SeekFrom::Start(at))?;
+                w.write_all(&block)?;
+                w.set_len(at + block.len() as u64)
+            });
+        }
+        match &self.background {
+            Some(jobs) => jobs
+                .send(IndexJob::Write(content))
+                .map_err(|_| index_writer_stopped()),
+            None => write_index_file(&self.writer, &self.path, self.io_retries, &content),
+        }
+    }
+    /// Moves writes of the index file to a thread, see [Options::background_index_writes].
+    /// Disabling it waits for the queued writes. Ignored for an index stored in the db file.
+    pub fn set_background_writes(&mut self, enabled: bool) -> Result<()> {
+        self.wait_background()?;
+        self.background = (enabled && self.embedded_at.is_none())
+            .then(|| spawn_index_writer(self.writer.clone(), self.path.clone(), self.io_retries));
+        Ok(())
+    }
+    /// Waits until the index writer thread (if any) wrote everything queued so far.
+    fn wait_background(&self) -> Result<()> {
+        let Some(jobs) = &self.background else {
+            return Ok(());
+        };
+        let (reply, done) = mpsc::channel();
+        jobs.send(IndexJob::Wait(reply))
+            .map_err(|_| index_writer_stopped())?;
+        done.recv().map_err(|_| index_writer_stopped())?
+    }
+    /// Switches the index file to the binary format or back to text lines, starting with the
+    /// next write, see [Options::binary_index]. Ignored for an index stored in the db file.
+    pub fn set_binary(&mut self, binary: bool) {
+        self.binary = binary && self.embedded_at.is_none();
     }
-    pub fn get_entry(&self, key: &str) -> Option<IndexEntry> {
-        self.entries.iter().find(|i| i.key == key).cloned()
+    /// Returns `true` if the index file is in the binary format.
+    pub fn is_binary(&self) -> bool {
+        self.binary
     }
-    pub fn write_index(&mut self) {
-        let string = Index::index_to_string(self);
-        let mut binding = self.writer.lock().unwrap();
-        let w = binding.get_mut();
-        w.seek(SeekFrom::Start(0)).unwrap();
-        w.set_len(0).unwrap();
-        w.write_all(string.as_bytes()).unwrap();
+    /// Sets whether entries are written with a checksum, starting with the next write, see
+    /// [Options::index_checksums]. Checksums that are present are verified either way.
+    pub fn set_checksums(&mut self, enabled: bool) {
+        self.checksums = enabled;
+    }
+    /// Returns the content of the index file in its current format.
+    fn file_bytes(&self) -> Vec<u8> {
+        if self.binary {
+            Index::index_to_bytes(self)
+        } else {
+            Index::index_to_string(self).into_bytes()
+        }
     }
     pub fn index_to_string(index: &Index) -> String {
+        Index::entries_to_string(&index.entries, index.checksums)
+    }
+    fn entries_to_string(entries: &[IndexEntry], checksums: bool) -> String {
         let mut str = String::new();
-        for i in index.entries.iter() {
-            str.push_str(&i.key);
-            str.push('=');
-            let range = [i.range.start.to_string(), i.range.end.to_string()].join("_");
-            str.push_str(&range);
+        for i in entries.iter() {
+            let line = Index::entry_line(i);
+            str.push_str(&line);
+            if checksums {
+                push_checksum(&mut str, &line);
+            }
             str.push('\n');
         }
         str
     }
-    pub fn parse_index(file: String) -> Vec<IndexEntry> {
+    /// Returns the text index line of `i` without its checksum and newline.
+    fn entry_line(i: &IndexEntry) -> String {
+        let mut str = String::new();
+        // a text index must not start with [BINARY_INDEX_MAGIC]:
+        if i.key.starts_with('m') {
+            str.push('\\');
+        }
+        str.push_str(&escape_field(&i.key));
+        str.push('=');
+        let range = [i.range.start.to_string(), i.range.end.to_string()].join("_");
+        str.push_str(&range);
+        Index::push_fields(&mut str, i);
+        str
+    }
+    /// Serializes the index in the binary format, see [BINARY_INDEX_MAGIC].
+    /// # Example
+    /// ```
+    /// use mu_db::Index;
+    /// let mut index = Index::new("./index_test_index_to_bytes.db").unwrap();
+    /// index.set_all_entries(Index::parse_index("a=0_5=tags:x\nb=5_9\n".to_string()).unwrap());
+    /// let bytes = Index::index_to_bytes(&index);
+    /// assert_eq!(&bytes[..8], mu_db::BINARY_INDEX_MAGIC);
+    /// assert_eq!(&bytes[8..13], &[1, 0, 0, 0, b'a']);
+    /// let text = Index::index_to_string(&index);
+    /// index.set_all_entries(Index::parse_index_binary(&bytes).unwrap());
+    /// assert_eq!(Index::index_to_string(&index), text);
+    /// ```
+    pub fn index_to_bytes(index: &Index) -> Vec<u8> {
+        let mut bytes = BINARY_INDEX_MAGIC.to_vec();
+        for i in index.entries.iter() {
+            let mut fields = String::new();
+            Index::push_fields(&mut fields, i);
+            if index.checksums {
+                push_checksum(&mut fields, &Index::entry_line(i));
+            }
+            bytes.extend_from_slice(&(i.key.len() as u32).to_le_bytes());
+            bytes.extend_from_slice(i.key.as_bytes());
+            bytes.extend_from_slice(&(i.range.start as u64).to_le_bytes());
+            bytes.extend_from_slice(&(i.range.end as u64).to_le_bytes());
+            bytes.extend_from_slice(&(fields.len() as u32).to_le_bytes());
+            bytes.extend_from_slice(fields.as_bytes());
+        }
+        bytes
+    }
+    /// Appends the optional `=name:value` fields of `i` to `str`.
+    fn push_fields(str: &mut String, i: &IndexEntry) {
+        if !i.tags.is_empty() {
+            let tags: Vec<String> = i.tags.iter().map(|t| escape_field(t)).collect();
+            str.push_str("=tags:");
+            str.push_str(&tags.join(","));
+        }
+        if i.version != 0 {
+            str.push_str("=version:");
+            str.push_str(&i.version.to_string());
+        }
+        if i.reserved != 0 {
+            str.push_str("=reserved:");
+            str.push_str(&i.reserved.to_string());
+        }
+        if let Some(content_type) = &i.content_type {
+            str.push_str("=content_type:");
+            str.push_str(&escape_field(content_type));
+        }
+        if let Some(value) = &i.inline {
+            str.push_str("=inline:");
+            str.push_str(&escape_field(value));
+        }
+    }
+    /// Parses the content of an index file.
+    ///
+    /// Returns an [IndexParseError] with the (1-based) line number and text of the first
+    /// malformed line, its `path` is set by the caller.
+    /// # Example
+    /// ```
+    /// use mu_db::Index;
+    /// let entries = Index::parse_index("a=0_5\nb=5_9\n".to_string()).unwrap();
+    /// assert_eq!(entries.len(), 2);
+    /// let entries = Index::parse_index("a=0_0=inline:a  \r\n".to_string()).unwrap();
+    /// assert_eq!(entries[0].inline_value(), Some("a  \r"));
+    ///
+    /// let err = Index::parse_index("a=0_5\nb=5x9\n".to_string()).unwrap_err();
+    /// assert_eq!(err.line, 2);
+    /// assert_eq!(err.content, "b=5x9");
+    /// assert_eq!(
+    ///     err.with_path("index_test.db").to_string(),
+    ///     "index_test.db:2: range is not in `start_end` form in index line `b=5x9`"
+    /// );
+    /// ```
+    /// Separators in keys are backslash-escaped, so every key round-trips:
+    /// ```
+    /// use mu_db::{DataBase, Index};
+    /// let mut db = DataBase::new("./test_parse_escaped.db").unwrap();
+    /// db.clear_all().unwrap();
+    /// let keys = ["a=b", "a_b", "a,b", "tag:x", "back\\slash", "line\nbreak", "crlf\r\n", "ключ 🔑"];
+    /// for key in keys {
+    ///     db.insert(key, key);
+    /// }
+    /// let content = std::fs::read_to_string("./index_test_parse_escaped.db").unwrap();
+    /// assert_eq!(Index::parse_index(content).unwrap().len(), keys.len());
+    /// drop(db);
+    /// let mut db = DataBase::new("./test_parse_escaped.db").unwrap();
+    /// for key in keys {
+    ///     assert_eq!(db.get(key).as_deref(), Some(key));
+    /// }
+    /// ```
+    pub fn parse_index(file: String) -> std::result::Result<Vec<IndexEntry>, IndexParseError> {
+        Index::parse_index_with_capacity(file, 0)
+    }
+    /// Same as `parse_index` with room for at least `capacity` entries.
+    pub fn parse_index_with_capacity(
+        file: String,
+        capacity: usize,
+    ) -> std::result::Result<Vec<IndexEntry>, IndexParseError> {
+        let lines = file.bytes().filter(|&b| b == b'\n').count() + 1;
+        let mut entries = Vec::with_capacity(capacity.max(lines));
         if file.is_empty() {
-            Vec::new()
-        } else {
-            let entries: Vec<IndexEntry> = file
-                .trim_end()
-                .split("\n")
-                .map(|i| {
-                    let entry: Vec<&str> = i.split("=").collect();
-                    let range: Vec<&str> = entry[1].split("_").collect();
-                    let range: Range<usize> = Range {
-                        start: range[0].parse().unwrap(),
-                        end: range[1].parse().unwrap(),
-                    };
-                    IndexEntry {
-                        key: entry[0].to_string(),
-                        range,
-                    }
-                })
-                .collect();
-            entries
+            return Ok(entries);
+        }
+        // only the last newline ends the file, spaces before it belong to the last line:
+        let content = file.strip_suffix('\n').unwrap_or(&file);
+        let parsed = content.split('\n').enumerate().map(|(n, i)| {
+            let err = |reason: &str| IndexParseError {
+                path: None,
+                line: n + 1,
+                content: i.to_string(),
+                reason: reason.to_string(),
+            };
+            let entry = split_escaped(i, '=');
+            if entry.len() < 2 {
+                return Err(err("missing `=` between key and range"));
+            }
+            let range: Vec<&str> = entry[1].split('_').collect();
+            if range.len() != 2 {
+                return Err(err("range is not in `start_end` form"));
+            }
+            let range: Range<usize> = Range {
+                start: range[0].parse().map_err(|_| err("invalid range start"))?,
+                end: range[1].parse().map_err(|_| err("invalid range end"))?,
+            };
+            if range.start > range.end {
+                return Err(err("range start is after range end"));
+            }
+            let mut index_entry = IndexEntry::new(unescape_field(entry[0]), range);
+            index_entry.version = 0;
+            Index::parse_fields(&mut index_entry, entry[2..].iter().copied()).map_err(err)?;
+            Ok(index_entry)
+        });
+        for entry in parsed {
+            entries.push(entry?);
+        }
+        Ok(entries)
+    }
+    /// Sets the optional `name:value` fields of `entry`, then checks it against its `crc`
+    /// field (if any), see [Options::index_checksums].
+    fn parse_fields<'a>(
+        entry: &mut IndexEntry,
+        fields: impl Iterator<Item = &'a str>,
+    ) -> std::result::Result<(), &'static str> {
+        let mut crc = None;
+        for field in fields {
+            match field.strip_prefix("crc:") {
+                Some(v) => crc = Some(u32::from_str_radix(v, 16).map_err(|_| "invalid checksum")?),
+                None => Index::parse_field(entry, field)?,
+            }
+        }
+        match crc {
+            Some(crc) if crc != crc32(Index::entry_line(entry).as_bytes()) => {
+                Err("checksum mismatch")
+            }
+            _ => Ok(()),
+        }
+    }
+    /// Sets the optional `name:value` field of `entry`, unknown names are skipped.
+    fn parse_field(entry: &mut IndexEntry, field: &str) -> std::result::Result<(), &'static str> {
+        match field.split_once(':') {
+            Some(("tags", v)) => {
+                entry.tags = split_escaped(v, ',')
+                    .into_iter()
+                    .map(unescape_field)
+                    .collect()
+            }
+            Some(("version", v)) => entry.version = v.parse().map_err(|_| "invalid version")?,
+            Some(("reserved", v)) => {
+                entry.reserved = v.parse().map_err(|_| "invalid reserved size")?
+            }
+            Some(("content_type", v)) => entry.content_type = Some(unescape_field(v)),
+            Some(("inline", v)) => entry.inline = Some(unescape_field(v)),
+            _ => {}
+        }
+        Ok(())
+    }
+    /// Parses the content of an index file in the binary format, see [BINARY_INDEX_MAGIC].
+    ///
+    /// Returns an [IndexParseError] for the first malformed or truncated entry, with the
+    /// (1-based) entry number as `line` and its key (if it could be read) as `content`.
+    /// # Example
+    /// ```
+    /// use mu_db::Index;
+    /// let mut bytes = mu_db::BINARY_INDEX_MAGIC.to_vec();
+    /// bytes.extend_from_slice(&[1, 0, 0, 0, b'a']);
+    /// bytes.extend_from_slice(&0u64.to_le_bytes());
+    /// bytes.extend_from_slice(&5u64.to_le_bytes());
+    /// bytes.extend_from_slice(&[0, 0, 0, 0]);
+    /// assert_eq!(Index::parse_index_binary(&bytes).unwrap().len(), 1);
+    ///
+    /// let err = Index::parse_index_binary(&bytes[..bytes.len() - 6]).unwrap_err();
+    /// assert_eq!(err.line, 1);
+    /// assert_eq!(err.content, "a");
+    /// assert_eq!(err.reason, "truncated range");
+    /// ```
+    pub fn parse_index_binary(
+        bytes: &[u8],
+    ) -> std::result::Result<Vec<IndexEntry>, IndexParseError> {
+        Index::parse_index_binary_with_capacity(bytes, 0)
+    }
+    /// Same as `parse_index_binary` with room for at least `capacity` entries.
+    fn parse_index_binary_with_capacity(
+        bytes: &[u8],
+        capacity: usize,
+    ) -> std::result::Result<Vec<IndexEntry>, IndexParseError> {
+        let mut entries = Vec::with_capacity(capacity);
+        let err = |line: usize, content: &str, reason: &str| IndexParseError {
+            path: None,
+            line,
+            content: content.to_string(),
+            reason: reason.to_string(),
+        };
+        let Some(mut rest) = bytes.strip_prefix(BINARY_INDEX_MAGIC) else {
+            return Err(err(0, "", "missing binary index header"));
+        };
+        while !rest.is_empty() {
+            let n = entries.len() + 1;
+            let key = take_prefixed(&mut rest).ok_or_else(|| err(n, "", "truncated key"))?;
+            let key = std::str::from_utf8(key).map_err(|_| err(n, "", "key is not UTF-8"))?;
+            let (Some(start), Some(end)) = (take_u64(&mut rest), take_u64(&mut rest)) else {
+                return Err(err(n, key, "truncated range"));
+            };
+            if start > end {
+                return Err(err(n, key, "range start is after range end"));
+            }
+            let fields = take_prefixed(&mut rest).ok_or_else(|| err(n, key, "truncated fields"))?;
+            let fields =
+                std::str::from_utf8(fields).map_err(|_| err(n, key, "fields are not UTF-8"))?;
+            let mut entry = IndexEntry::new(key.to_string(), start as usize..end as usize);
+            entry.version = 0;
+            // the fields start with a `=`:
+            let fields = split_escaped(fields, '=').into_iter().skip(1);
+            Index::parse_fields(&mut entry, fields).map_err(|reason| err(n, key, reason))?;
+            entries.push(entry);
         }
+        Ok(entries)
     }
     pub fn clear_all(&mut self) {
         self.entries.clear();
-        self.writer.lock().unwrap().get_mut().set_len(0).unwrap();
+        self.free = None;
+        if let Some(order) = self.key_order.as_mut() {
+            order.clear();
+        }
+        if let Some(hashes) = self.key_hashes.as_mut() {
+            hashes.clear();
+        }
+        self.tags.clear();
+        if let Some(o) = self.offload.as_mut() {
+            o.fences.clear();
+            o.count = 0;
+            o.end = 0;
+            o.removed.clear();
+            o.dirty = false;
+            o.complete = false;
+        }
+        if self.embedded_at.is_some() {
+            self.write_index();
+        } else {
+            self.wait_background().unwrap();
+            self.writer.lock().unwrap().get_mut().set_len(0).unwrap();
+        }
     }
     pub fn get_all_entries(&self) -> Vec<IndexEntry> {
         self.entries.clone()
     }
+    /// Replaces all entries, a key that appears more than once keeps its last entry, see
+    /// (`.set_all_entries_with()`).
     pub fn set_all_entries(&mut self, entries: Vec<IndexEntry>) {
+        self.set_all_entries_with(entries, DuplicateKeyPolicy::KeepLast)
+            .unwrap();
+    }
+    /// Replaces all entries, resolving keys that appear more than once with `policy`.
+    ///
+    /// With [DuplicateKeyPolicy::Error] an `InvalidInput` error naming the first duplicate
+    /// key is returned and the index is left unchanged.
+    /// # Example
+    /// ```
+    /// use mu_db::{DuplicateKeyPolicy, Index};
+    /// let mut index = Index::new("./index_test_set_all_entries.db").unwrap();
+    /// let entries = Index::parse_index("a=0_3\nb=3_4\na=4_9\n".to_string()).unwrap();
+    /// assert!(index
+    ///     .set_all_entries_with(entries.clone(), DuplicateKeyPolicy::Error)
+    ///     .is_err());
+    /// index
+    ///     .set_all_entries_with(entries.clone(), DuplicateKeyPolicy::KeepFirst)
+    ///     .unwrap();
+    /// assert_eq!(index.get_entry("a").unwrap().size(), 3);
+    /// index.set_all_entries(entries);
+    /// assert_eq!(index.get_all_entries().len(), 2);
+    /// assert_eq!(index.get_entry("a").unwrap().size(), 5);
+    /// ```
+    pub fn set_all_entries_with(
+        &mut self,
+        mut entries: Vec<IndexEntry>,
+        policy: DuplicateKeyPolicy,
+    ) -> Result<()> {
+        let mut seen = HashSet::new();
+        let mut duplicate = None;
+        if policy == DuplicateKeyPolicy::KeepLast {
+            entries.reverse();
+        }
+        entries.retain(|e| {
+            let first = seen.insert(e.key.clone());
+            if !first && duplicate.is_none() {
+                duplicate = Some(e.key.clone());
+            }
+            first
+        });
+        if policy == DuplicateKeyPolicy::KeepLast {
+            entries.reverse();
+        }
+        if let (DuplicateKeyPolicy::Error, Some(key)) = (policy, duplicate) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("duplicate key `{}` in entries", key),
+            ));
+        }
         self.entries = entries;
+        if let Some(o) = self.offload.as_mut() {
+            // the index file isn't merged in:
+            o.count = 0;
+            o.complete = true;
+            o.dirty = true;
+        }
+        self.free = None;
+        self.rebuild_tags();
+        self.rebuild_key_order();
         self.write_index();
+        Ok(())
     }
     /// Returns old `self.entries`
     pub fn shrink_entries(&mut self) -> Vec<IndexEntry> {
+        self.load_all().unwrap();
         let old = self.entries.clone();
         if old.is_empty() {
             return old;
         }
 
+        self.free = None;
+        for e in self.entries.iter_mut() {
+            e.reserved = 0;
+        }
+        // entries never move forward, even one found inside the reserved prefix:
         let first = &mut self.entries[0].range;
-        if first.start != 0 {
-            first.end -= first.start;
-            first.start = 0;
+        if first.start > self.reserved_prefix {
+            first.end -= first.start - self.reserved_prefix;
+            first.start = self.reserved_prefix;
         }
         for i in 0..self.entries.len() - 1 {
             let curr = self.entries[i].range.clone();
@@ -494,12 +5524,735 @@ impl Index {
         }
 
         self.write_index();
-        return old;
+        old
+    }
+    /// Releases spare capacity of `self.entries` and the lookup structures.
+    pub fn shrink_to_fit(&mut self) {
+        self.entries.shrink_to_fit();
+        self.tags.shrink_to_fit();
+        if let Some(order) = self.key_order.as_mut() {
+            order.shrink_to_fit();
+        }
+        if let Some(hashes) = self.key_hashes.as_mut() {
+            hashes.shrink_to_fit();
+        }
+    }
+    /// Returns an estimate of the bytes held by `self.entries` and its keys.
+    pub fn memory_usage(&self) -> usize {
+        std::mem::size_of::<Vec<IndexEntry>>()
+            + self.entries.capacity() * std::mem::size_of::<IndexEntry>()
+            + self
+                .entries
+                .iter()
+                .map(|i| {
+                    i.key.capacity()
+                        + i.tags.capacity() * std::mem::size_of::<String>()
+                        + i.tags.iter().map(|t| t.capacity()).sum::<usize>()
+                        + i.content_type.as_ref().map_or(0, |c| c.capacity())
+                })
+                .sum::<usize>()
+            + self
+                .tags
+                .iter()
+                .map(|(tag, keys)| {
+                    tag.capacity()
+                        + keys.capacity() * std::mem::size_of::<String>()
+                        + keys.iter().map(|k| k.capacity()).sum::<usize>()
+                })
+                .sum::<usize>()
+            + self
+                .key_order
+                .as_ref()
+                .map_or(0, |o| o.capacity() * std::mem::size_of::<usize>())
+            + self.key_hashes.as_ref().map_or(0, |h| {
+                h.capacity() * std::mem::size_of::<(u64, Vec<usize>)>()
+                    + h.values()
+                        .map(|b| b.capacity() * std::mem::size_of::<usize>())
+                        .sum::<usize>()
+            })
+            + self.offload.as_ref().map_or(0, |o| {
+                o.fences.capacity() * std::mem::size_of::<(String, u64)>()
+                    + o.fences.iter().map(|(k, _)| k.capacity()).sum::<usize>()
+                    + o.removed.iter().map(|k| k.capacity()).sum::<usize>()
+                    + o.recent.iter().map(|k| k.capacity()).sum::<usize>()
+            })
+    }
+    /// Adds `tag` to the entry of `key`, returns `false` if the key doesn't exist.
+    pub fn add_tag(&mut self, key: &str, tag: &str) -> bool {
+        self.promote(key).unwrap();
+        let Some(pos) = self.position(key) else {
+            return false;
+        };
+        let entry = &mut self.entries[pos];
+        if !entry.tags.iter().any(|t| t == tag) {
+            entry.tags.push(tag.to_string());
+            self.tags
+                .entry(tag.to_string())
+                .or_default()
+                .insert(key.to_string());
+            self.write_index();
+        }
+        true
+    }
+    /// Removes `tag` from the entry of `key`, returns `false` if the entry didn't have it.
+    pub fn remove_tag(&mut self, key: &str, tag: &str) -> bool {
+        self.promote(key).unwrap();
+        let Some(pos) = self.position(key) else {
+            return false;
+        };
+        let entry = &mut self.entries[pos];
+        let Some(pos) = entry.tags.iter().position(|t| t == tag) else {
+            return false;
+        };
+        entry.tags.remove(pos);
+        if let Some(keys) = self.tags.get_mut(tag) {
+            keys.remove(key);
+            if keys.is_empty() {
+                self.tags.remove(tag);
+            }
+        }
+        self.write_index();
+        true
+    }
+    /// Returns up to `limit` sorted keys greater than `after`, and whether more keys follow.
+    pub fn keys_after(&self, after: Option<&str>, limit: usize) -> (Vec<String>, bool) {
+        let is_after = |key: &str| after.is_none_or(|a| key > a);
+        let mut keys: Vec<&str> = match &self.key_order {
+            Some(order) => {
+                let start = order.partition_point(|&i| !is_after(&self.entries[i].key));
+                order[start..]
+                    .iter()
+                    .take(limit.saturating_add(1))
+                    .map(|&i| self.entries[i].key.as_str())
+                    .collect()
+            }
+            None => {
+                let mut keys: Vec<&str> = self
+                    .entries
+                    .iter()
+                    .map(|e| e.key.as_str())
+                    .filter(|k| is_after(k))
+                    .collect();
+                keys.sort_unstable();
+                keys
+            }
+        };
+        let more = keys.len() > limit;
+        keys.truncate(limit);
+        (keys.into_iter().map(String::from).collect(), more)
+    }
+    /// Sets the content type of the entry of `key`, returns `false` if the key doesn't exist.
+    pub fn set_content_type(&mut self, key: &str, content_type: Option<&str>) -> bool {
+        self.promote(key).unwrap();
+        let Some(pos) = self.position(key) else {
+            return false;
+        };
+        self.entries[pos].content_type = content_type.map(String::from);
+        self.write_index();
+        true
+    }
+    /// Returns the keys tagged with `tag`, sorted.
+    pub fn keys_with_tag(&self, tag: &str) -> Vec<String> {
+        let mut keys: Vec<String> = self
+            .tags
+            .get(tag)
+            .map(|keys| keys.iter().cloned().collect())
+            .unwrap_or_default();
+        keys.sort();
+        keys
+    }
+    fn untag_all(&mut self, entry: &IndexEntry) {
+        for tag in entry.tags.iter() {
+            if let Some(keys) = self.tags.get_mut(tag) {
+                keys.remove(&entry.key);
+                if keys.is_empty() {
+                    self.tags.remove(tag);
+                }
+            }
+        }
+    }
+    fn tag_all(&mut self, pos: usize) {
+        let entry = &self.entries[pos];
+        for tag in entry.tags.iter() {
+            self.tags
+                .entry(tag.clone())
+                .or_default()
+                .insert(entry.key.clone());
+        }
+    }
+    fn rebuild_key_order(&mut self) {
+        if let Some(order) = self.key_order.as_mut() {
+            *order = (0..self.entries.len()).collect();
+            order.sort_by(|&a, &b| self.entries[a].key.cmp(&self.entries[b].key));
+        }
+        if let Some(hashes) = self.key_hashes.as_mut() {
+            hashes.clear();
+            for (i, entry) in self.entries.iter().enumerate() {
+                hashes
+                    .entry(DataBase::hash_key(&entry.key))
+                    .or_default()
+                    .push(i);
+            }
+        }
+    }
+    fn rebuild_tags(&mut self) {
+        self.tags.clear();
+        for entry in self.entries.iter() {
+            for tag in entry.tags.iter() {
+                self.tags
+                    .entry(tag.clone())
+                    .or_default()
+                    .insert(entry.key.clone());
+            }
+        }
     }
 }
 
 impl IndexEntry {
+    fn new(key: String, range: Range<usize>) -> Self {
+        IndexEntry {
+            key,
+            range,
+            tags: Vec::new(),
+            version: 1,
+            reserved: 0,
+            content_type: None,
+            inline: None,
+        }
+    }
     pub fn size(&self) -> usize {
         self.range.end - self.range.start
     }
+    /// Size plus the bytes reserved after the value, see [ShrinkTailPolicy].
+    pub fn capacity(&self) -> usize {
+        self.size() + self.reserved
+    }
+    /// End of the bytes owned by this entry, including reserved ones.
+    fn occupied_end(&self) -> usize {
+        self.range.end + self.reserved
+    }
+    pub fn tags(&self) -> &[String] {
+        &self.tags
+    }
+    /// Number of writes to this entry, `0` if unknown (written by an older version).
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+    /// Returns the content type set by (`.insert_with_content_type()`).
+    pub fn content_type(&self) -> Option<&str> {
+        self.content_type.as_deref()
+    }
+    /// Returns the value if it's stored in the index instead of the db file.
+    pub fn inline_value(&self) -> Option<&str> {
+        self.inline.as_deref()
+    }
+}
+
+/// An inconsistency found by [DataBase::verify].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Issue {
+    /// The key has more than one entry.
+    DuplicateKey(String),
+    /// The ranges of the two keys overlap or aren't in offset order.
+    Overlap(String, String),
+    /// The range of the key ends past the end of the db file.
+    OutOfBounds {
+        key: String,
+        end: usize,
+        buf_len: u64,
+    },
+}
+
+/// One operation of (`.apply()`).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Op {
+    Insert { key: String, value: String },
+    Remove { key: String },
+}
+
+impl Op {
+    pub fn insert(key: &str, value: &str) -> Self {
+        Op::Insert {
+            key: key.to_string(),
+            value: value.to_string(),
+        }
+    }
+    pub fn remove(key: &str) -> Self {
+        Op::Remove {
+            key: key.to_string(),
+        }
+    }
+}
+
+/// Result of [DataBase::diff], each list is sorted.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Diff {
+    pub only_in_self: Vec<String>,
+    pub only_in_other: Vec<String>,
+    /// Keys present in both with different values.
+    pub different: Vec<String>,
+}
+
+impl Diff {
+    /// Returns `true` if both databases hold the same keys and values.
+    pub fn is_empty(&self) -> bool {
+        self.only_in_self.is_empty() && self.only_in_other.is_empty() && self.different.is_empty()
+    }
+}
+
+/// What (`.repair()`) and (`DataBase::open_and_repair()`) did.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RepairReport {
+    /// Issues found by (`.verify()`) before repairing.
+    pub issues: Vec<Issue>,
+    /// Keys of the entries dropped from the index, in the order they were dropped.
+    pub dropped: Vec<String>,
+    /// Index file lines (1-based) that couldn't be parsed and were dropped.
+    pub unparsed_lines: Vec<usize>,
+}
+
+impl RepairReport {
+    /// Returns `true` if nothing needed repairing.
+    pub fn is_clean(&self) -> bool {
+        self.issues.is_empty() && self.unparsed_lines.is_empty()
+    }
+}
+
+/// Result of [DataBase::health].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Health {
+    Ok,
+    /// Enough space is unused that (`.shrink()`) is worth running.
+    FragmentedShouldShrink {
+        reclaimable: u64,
+    },
+    /// (`.verify()`) found issues.
+    Corrupt {
+        issues: Vec<Issue>,
+    },
+}
+
+/// Error returned by [Index::parse_index] for a malformed index line.
+///
+/// Opening a database whose index file is malformed, e.g. cut off by a power loss during an
+/// index write, returns it (wrapped in an `InvalidData` [std::io::Error]) instead of panicking.
+/// # Example
+/// ```
+/// // the test truncates the index file, so start without one
+/// let _ = std::fs::remove_file("./index_test_truncated_index.db");
+/// let mut db = mu_db::DataBase::new("./test_truncated_index.db").unwrap();
+/// db.clear_all().unwrap();
+/// db.insert("k1", "hello");
+/// db.insert("k2", "world");
+/// drop(db);
+/// std::fs::write("./index_test_truncated_index.db", "k1=0_5\nk2=5_").unwrap();
+///
+/// let err = mu_db::DataBase::new("./test_truncated_index.db").err().unwrap();
+/// assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+/// let parse_err = err.get_ref().unwrap().downcast_ref::<mu_db::IndexParseError>();
+/// assert_eq!(parse_err.unwrap().line, 2);
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IndexParseError {
+    /// Path of the index file, if known.
+    pub path: Option<PathBuf>,
+    /// 1-based line number of the malformed line.
+    pub line: usize,
+    /// Text of the malformed line.
+    pub content: String,
+    /// What is wrong with the line.
+    pub reason: String,
+}
+
+impl IndexParseError {
+    pub fn with_path(mut self, path: impl AsRef<Path>) -> Self {
+        self.path = Some(path.as_ref().to_path_buf());
+        self
+    }
+}
+
+impl fmt::Display for IndexParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.path {
+            Some(path) => write!(f, "{}:{}: ", path.display(), self.line)?,
+            None => write!(f, "line {}: ", self.line)?,
+        }
+        write!(f, "{} in index line `{}`", self.reason, self.content)
+    }
+}
+
+impl std::error::Error for IndexParseError {}
+
+impl From<IndexParseError> for std::io::Error {
+    fn from(e: IndexParseError) -> Self {
+        std::io::Error::new(std::io::ErrorKind::InvalidData, e)
+    }
+}
+
+/// Error returned when opening a file written in another [FORMAT_VERSION].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VersionMismatch {
+    /// Path of the db file, if known.
+    pub path: Option<PathBuf>,
+    /// Version found in the file.
+    pub found: u32,
+}
+
+impl fmt::Display for VersionMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(path) = &self.path {
+            write!(f, "{}: ", path.display())?;
+        }
+        write!(
+            f,
+            "unsupported format version {} (supported: {})",
+            self.found, FORMAT_VERSION
+        )
+    }
+}
+
+impl std::error::Error for VersionMismatch {}
+
+impl From<VersionMismatch> for std::io::Error {
+    fn from(e: VersionMismatch) -> Self {
+        std::io::Error::new(std::io::ErrorKind::InvalidData, e)
+    }
+}
+
+/// Returns the version byte of `footer` if it is `magic` with any version.
+fn footer_version(footer: &[u8], magic: &[u8; 8]) -> Option<u32> {
+    (footer.len() == 8 && footer[..7] == magic[..7]).then(|| footer[7].wrapping_sub(b'0') as u32)
+}
+
+/// Replaces the content of the index file at `path` with `index`, then points `writer` at the
+/// new file.
+///
+/// `index` is written to a sibling `<path>.tmp` that is renamed over `path`, so an interrupted
+/// write leaves either the old or the new index, never a truncated one. The new file is synced
+/// before the rename and (on Unix) its directory after it, so this holds after a power loss too.
+fn write_index_file(
+    writer: &Mutex<BufWriter<File>>,
+    path: &Path,
+    io_retries: usize,
+    index: &[u8],
+) -> Result<()> {
+    let mut binding = writer.lock().unwrap();
+    let mut tmp = path.as_os_str().to_os_string();
+    tmp.push(".tmp");
+    let tmp = PathBuf::from(tmp);
+    let written = retry_transient(io_retries, || {
+        let mut file = File::create(&tmp)?;
+        file.write_all(index)?;
+        file.sync_all()?;
+        std::fs::rename(&tmp, path)?;
+        #[cfg(unix)]
+        File::open(parent_dir(path))?.sync_all()?;
+        Ok(file)
+    });
+    match written {
+        // the old handle still points at the replaced file:
+        Ok(file) => {
+            *binding = BufWriter::new(file);
+            Ok(())
+        }
+        Err(e) => {
+            let _ = std::fs::remove_file(&tmp);
+            Err(e)
+        }
+    }
+}
+
+/// Starts the thread that writes the index file for [Options::background_index_writes]. It
+/// stops once every sender of the returned queue is dropped.
+fn spawn_index_writer(
+    writer: Arc<Mutex<BufWriter<File>>>,
+    path: PathBuf,
+    io_retries: usize,
+) -> mpsc::Sender<IndexJob> {
+    let (jobs, queue) = mpsc::channel();
+    std::thread::spawn(move || {
+        let mut error = None;
+        let mut next = queue.recv().ok();
+        while let Some(job) = next.take() {
+            match job {
+                IndexJob::Write(mut index) => {
+                    // every write replaces the whole file, only the latest queued one matters:
+                    loop {
+                        match queue.try_recv() {
+                            Ok(IndexJob::Write(newer)) => index = newer,
+                            Ok(job) => {
+                                next = Some(job);
+                                break;
+                            }
+                            Err(_) => break,
+                        }
+                    }
+                    if let Err(e) = write_index_file(&writer, &path, io_retries, &index) {
+                        error.get_or_insert(e);
+                    }
+                }
+                IndexJob::Wait(reply) => {
+                    let _ = reply.send(error.take().map_or(Ok(()), Err));
+                }
+            }
+            if next.is_none() {
+                next = queue.recv().ok();
+            }
+        }
+    });
+    jobs
+}
+
+fn index_writer_stopped() -> std::io::Error {
+    std::io::Error::other("index writer thread stopped")
+}
+
+/// Starts the thread that writes values for [Options::background_data_writes]. It stops once
+/// every sender of the returned queue is dropped.
+fn spawn_data_writer(
+    writer: Arc<Mutex<BufWriter<File>>>,
+    io_retries: usize,
+) -> mpsc::Sender<DataJob> {
+    let (jobs, queue) = mpsc::channel();
+    std::thread::spawn(move || {
+        let mut error = None;
+        for job in queue {
+            match job {
+                DataJob::Write(start, content, flush_at) => {
+                    let mut bw = writer.lock().unwrap();
+                    if let Err(e) = write_data(&mut bw, start, &content, flush_at, io_retries) {
+                        error.get_or_insert(e);
+                    }
+                }
+                DataJob::Wait(reply) => {
+                    let _ = reply.send(error.take().map_or(Ok(()), Err));
+                }
+            }
+        }
+    });
+    jobs
+}
+
+/// Waits until the data writer thread (if any) wrote everything queued so far.
+fn wait_data_writer(background: &Option<mpsc::Sender<DataJob>>) -> Result<()> {
+    let Some(jobs) = background else {
+        return Ok(());
+    };
+    let (reply, done) = mpsc::channel();
+    jobs.send(DataJob::Wait(reply))
+        .map_err(|_| data_writer_stopped())?;
+    done.recv().map_err(|_| data_writer_stopped())?
+}
+
+fn data_writer_stopped() -> std::io::Error {
+    std::io::Error::other("data writer thread stopped")
+}
+
+/// Writes `content` at `start` through the db file writer, then flushes it unless fewer than
+/// `flush_at` bytes are buffered.
+fn write_data(
+    bw: &mut BufWriter<File>,
+    start: u64,
+    content: &[u8],
+    flush_at: usize,
+    io_retries: usize,
+) -> Result<()> {
+    // seeking flushes the buffer, so appends right after the last write skip it. The reader
+    // shares the file offset, so the offset is checked instead of remembering the last write:
+    let mut seek = (&mut bw.get_ref()).stream_position()? + bw.buffer().len() as u64 != start;
+    retry_transient(io_retries, || {
+        if seek {
+            bw.seek(SeekFrom::Start(start))?;
+        }
+        seek = true;
+        bw.write_all(content)?;
+        if bw.buffer().len() < flush_at {
+            Ok(())
+        } else {
+            bw.flush()
+        }
+    })
+}
+
+/// Wraps the db `file` in a writer whose buffer fits [Options::flush_every_bytes].
+fn data_writer(file: File, options: &Options) -> BufWriter<File> {
+    match options.flush_every_bytes {
+        Some(n) => BufWriter::with_capacity(n.max(8 * 1024), file),
+        None => BufWriter::new(file),
+    }
+}
+
+/// Opens (or creates) `path` for reading and writing, with a clear error if it's a directory.
+fn open_file(path: &str) -> Result<File> {
+    if Path::new(path).is_dir() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::IsADirectory,
+            format!("path is a directory: {}", path),
+        ));
+    }
+    OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)
+}
+
+/// Runs `op` until it succeeds, fails with a non-transient error or has been retried
+/// `retries` times. `op` must be safe to repeat, e.g. seek to an absolute position first.
+fn retry_transient<T>(retries: usize, mut op: impl FnMut() -> Result<T>) -> Result<T> {
+    let mut attempt = 0;
+    loop {
+        match op() {
+            Err(e)
+                if attempt < retries
+                    && matches!(
+                        e.kind(),
+                        std::io::ErrorKind::Interrupted | std::io::ErrorKind::WouldBlock
+                    ) =>
+            {
+                let backoff = (1u64 << attempt.min(7)).min(100);
+                std::thread::sleep(std::time::Duration::from_millis(backoff));
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+fn range_error(range: &Range<usize>, size: usize) -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::InvalidInput,
+        format!(
+            "range {:?} is out of bounds of a {} byte value",
+            range, size
+        ),
+    )
+}
+
+fn invalid_key(key: &str) -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::InvalidInput,
+        format!("invalid key `{}`", key),
+    )
+}
+
+fn boundary_error(offset: usize) -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::InvalidData,
+        format!("byte {} of the value isn't on a character boundary", offset),
+    )
+}
+
+/// Returns the path of the index file of the db file at `path`.
+fn index_path_for(path: &str) -> String {
+    let _path = Path::new(path);
+    let db_file_name = _path.file_name().and_then(|i| i.to_str()).unwrap();
+    let db_file_parent = _path
+        .parent()
+        .unwrap()
+        .to_str()
+        .map(|i| if i.is_empty() { "." } else { i })
+        .unwrap();
+    format!("{}/index_{}", db_file_parent, db_file_name)
+}
+
+/// Returns the directory containing `path`, `.` for a bare file name.
+fn parent_dir(path: &Path) -> &Path {
+    match path.parent() {
+        Some(p) if !p.as_os_str().is_empty() => p,
+        _ => Path::new("."),
+    }
+}
+
+/// Escapes `\`, `,`, `=` and newlines so a value fits in an index line field.
+fn escape_field(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            ',' => escaped.push_str("\\,"),
+            '=' => escaped.push_str("\\="),
+            '\n' => escaped.push_str("\\n"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Reverses `escape_field`.
+fn unescape_field(field: &str) -> String {
+    let mut value = String::with_capacity(field.len());
+    let mut chars = field.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some('n') => value.push('\n'),
+                Some(c) => value.push(c),
+                None => {}
+            },
+            c => value.push(c),
+        }
+    }
+    value
+}
+
+/// Appends the `=crc:` field of the index `line` to `str`, see [Options::index_checksums].
+fn push_checksum(str: &mut String, line: &str) {
+    str.push_str(&format!("=crc:{:08x}", crc32(line.as_bytes())));
+}
+
+/// CRC-32 (IEEE) of `bytes`.
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &b in bytes {
+        crc ^= b as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+/// Removes a little-endian `u32` length and that many bytes from the front of `rest`, returns
+/// the bytes. `None` if `rest` is too short.
+fn take_prefixed<'a>(rest: &mut &'a [u8]) -> Option<&'a [u8]> {
+    let (len, tail) = rest.split_first_chunk::<4>()?;
+    let len = u32::from_le_bytes(*len) as usize;
+    if tail.len() < len {
+        return None;
+    }
+    let (bytes, tail) = tail.split_at(len);
+    *rest = tail;
+    Some(bytes)
+}
+
+/// Removes a little-endian `u64` from the front of `rest`. `None` if `rest` is too short.
+fn take_u64(rest: &mut &[u8]) -> Option<u64> {
+    let (n, tail) = rest.split_first_chunk::<8>()?;
+    *rest = tail;
+    Some(u64::from_le_bytes(*n))
+}
+
+/// Splits `field` on every `sep` that isn't escaped, the parts are left escaped.
+fn split_escaped(field: &str, sep: char) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut start = 0;
+    let mut escaped = false;
+    for (i, c) in field.char_indices() {
+        if escaped {
+            escaped = false;
+        } else if c == '\\' {
+            escaped = true;
+        } else if c == sep {
+            parts.push(&field[start..i]);
+            start = i + c.len_utf8();
+        }
+    }
+    parts.push(&field[start..]);
+    parts
 }
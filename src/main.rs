@@ -5,7 +5,7 @@ use std::{io::Result, time::Instant};
 use mu_db::DataBase;
 
 fn main() -> Result<()> {
-    let mut db = DataBase::new("./test.db");
+    let mut db = DataBase::new("./test.db")?;
 
 
     // let mut str = String::new();